@@ -5,321 +5,2740 @@
 
 #![deny(missing_docs)]
 #![forbid(unsafe_code)]
-// Let's support legacy rustc
-#![allow(bare_trait_objects)]
 extern crate byteorder;
+#[cfg(target_os = "linux")]
+extern crate rustix;
+/// Re-exported so that TLS configuration can be built with matching version
+#[cfg(feature = "tls")]
+pub extern crate rustls;
 
 /// Information about an export (without name)
-#[derive(Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Clone, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct Export<Data = ()> {
     /// Size of the underlying data, in bytes
     pub size: u64,
     /// Tell client it's readonly
     pub readonly: bool,
-    /// Tell that NBD_CMD_RESIZE should be supported. Not implemented in this library currently
+    /// Tell that NBD_CMD_RESIZE should be supported. Server handles it with `BlockDevice::resize`
+    /// if `server::NegotiationOptions::resize_policy` allows.
     pub resizeable: bool,
     /// Tell that the exposed device has slow seeks, hence clients should use elevator algorithm
     pub rotational: bool,
-    /// Tell that NBD_CMD_TRIM operation is supported. Not implemented in this library currently
+    /// Tell that NBD_CMD_TRIM operation is supported. Server handles it with `BlockDevice::trim`.
     pub send_trim: bool,
     /// Tell that NBD_CMD_FLUSH may be sent
     pub send_flush: bool,
+    /// Tell that NBD_CMD_WRITE_ZEROES is supported. Server handles it with `BlockDevice::write_zeroes`,
+    /// falling back to writing zeroes the usual way.
+    pub send_write_zeroes: bool,
+    /// Tell that NBD_CMD_FLAG_FAST_ZERO may be sent with NBD_CMD_WRITE_ZEROES, requires
+    /// `send_write_zeroes`. Server then fails the request instead of falling back.
+    pub send_fast_zero: bool,
+    /// Tell that NBD_CMD_FLAG_FUA may be sent. Server flushes after each write carrying it.
+    pub send_fua: bool,
+    /// Tell that NBD_CMD_CACHE is supported. Server handles it with `BlockDevice::cache`,
+    /// falling back to reading and discarding the data.
+    pub send_cache: bool,
+    /// Canonical name of the export, reported as NBD_INFO_NAME.
+    /// Server uses the name requested by client if this is `None`.
+    pub name: Option<String>,
+    /// Human-readable description, reported as NBD_INFO_DESCRIPTION
+    pub description: Option<String>,
+    /// Block size constraints, reported as NBD_INFO_BLOCK_SIZE
+    pub block_size: Option<BlockSize>,
     /// Associated data for the export
     pub data: Data,
 }
 
+/// Name and description of an export, as reported by NBD_OPT_LIST
+#[derive(Debug, Clone, Default, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ListedExport {
+    /// Name to be used by client to select the export
+    pub name: String,
+    /// Optional human-readable description
+    pub description: Option<String>,
+}
+
+/// Block size constraints of an export
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct BlockSize {
+    /// Minimal block size. Power of two, no more than 65536.
+    pub minimum: u32,
+    /// Preferred block size. Power of two, at least `minimum` and at least 512.
+    pub preferred: u32,
+    /// Maximum size of a payload (or a request in general), multiple of `minimum`
+    pub maximum: u32,
+}
+
+/// Metadata context selected with NBD_OPT_SET_META_CONTEXT
+#[derive(Debug, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct MetaContext {
+    /// Identifier used in block status replies
+    pub id: u32,
+    /// Full name, like `base:allocation`
+    pub name: String,
+}
+
+/// Piece of a range described by block status (`base:allocation` meta context)
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Extent {
+    /// Length of the piece in bytes
+    pub length: u64,
+    /// Storage is not allocated (NBD_STATE_HOLE)
+    pub hole: bool,
+    /// Reads return zeroes (NBD_STATE_ZERO)
+    pub zero: bool,
+}
+
+/// Failure specific to NBD.
+///
+/// Functions of this library return `std::io::Error`, which carries this inside
+/// (except for transport errors). Convert with `Error::from` to inspect it.
+#[derive(Debug)]
+pub enum Error {
+    /// Peer violated the protocol or sent something this library does not understand
+    Protocol(&'static str),
+    /// Client has given up negotiation with NBD_OPT_ABORT
+    Aborted,
+    /// Server has refused an option during negotiation
+    Refused {
+        /// Which NBD_REP_ERR_* it was
+        reason: Refusal,
+        /// Human-readable message from server, may be empty
+        message: String,
+    },
+    /// Server has failed a command
+    Device {
+        /// Error code from the reply, like EIO
+        errno: u32,
+        /// Human-readable message from server, only sent along with structured replies
+        message: Option<String>,
+    },
+    /// Any other `std::io::Error`, usually from the underlying connection or storage
+    Transport(std::io::Error),
+}
+
+/// Reason of refusing an option (NBD_REP_ERR_*)
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Refusal {
+    /// NBD_REP_ERR_UNSUP: option is not known to server
+    Unsupported,
+    /// NBD_REP_ERR_POLICY: forbidden by server configuration
+    Policy,
+    /// NBD_REP_ERR_INVALID: malformed or out of place option
+    Invalid,
+    /// NBD_REP_ERR_PLATFORM: not supported on server's platform
+    Platform,
+    /// NBD_REP_ERR_TLS_REQD: TLS must be negotiated first
+    TlsRequired,
+    /// NBD_REP_ERR_UNKNOWN: no such export
+    UnknownExport,
+    /// NBD_REP_ERR_SHUTDOWN: server is shutting down
+    Shutdown,
+    /// NBD_REP_ERR_BLOCK_SIZE_REQD: client must acknowledge block size constraints
+    BlockSizeRequired,
+    /// Some other error reply type
+    Other(u32),
+}
+
+impl Refusal {
+    fn from_reply(rtype: u32) -> Refusal {
+        use consts::*;
+        match rtype {
+            NBD_REP_ERR_UNSUP => Refusal::Unsupported,
+            NBD_REP_ERR_POLICY => Refusal::Policy,
+            NBD_REP_ERR_INVALID => Refusal::Invalid,
+            NBD_REP_ERR_PLATFORM => Refusal::Platform,
+            NBD_REP_ERR_TLS_REQD => Refusal::TlsRequired,
+            NBD_REP_ERR_UNKNOWN => Refusal::UnknownExport,
+            NBD_REP_ERR_SHUTDOWN => Refusal::Shutdown,
+            NBD_REP_ERR_BLOCK_SIZE_REQD => Refusal::BlockSizeRequired,
+            x => Refusal::Other(x),
+        }
+    }
+
+    fn kind(self) -> std::io::ErrorKind {
+        use std::io::ErrorKind;
+        match self {
+            Refusal::Unsupported | Refusal::Platform => ErrorKind::Unsupported,
+            Refusal::Policy | Refusal::TlsRequired => ErrorKind::PermissionDenied,
+            Refusal::Invalid => ErrorKind::InvalidInput,
+            Refusal::UnknownExport => ErrorKind::NotFound,
+            Refusal::Shutdown => ErrorKind::ConnectionAborted,
+            Refusal::BlockSizeRequired | Refusal::Other(_) => ErrorKind::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Error::Protocol(s) => f.write_str(s),
+            Error::Aborted => f.write_str("Client abort"),
+            Error::Refused {
+                reason,
+                ref message,
+            } => {
+                write!(f, "Option refused ({:?})", reason)?;
+                if !message.is_empty() {
+                    write!(f, ": {}", message)?;
+                }
+                Ok(())
+            }
+            Error::Device { errno, ref message } => {
+                write!(f, "Error {} from device", errno)?;
+                if let Some(ref m) = *message {
+                    write!(f, ": {}", m)?;
+                }
+                Ok(())
+            }
+            Error::Transport(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Transport(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Get the error back from `std::io::Error` without consuming it
+    pub fn of(e: &std::io::Error) -> Option<&Error> {
+        e.get_ref().and_then(|x| x.downcast_ref())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        if Error::of(&e).is_none() {
+            return Error::Transport(e);
+        }
+        match e.into_inner().map(|x| x.downcast::<Error>()) {
+            Some(Ok(x)) => *x,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> std::io::Error {
+        use std::io::ErrorKind;
+        let kind = match e {
+            Error::Transport(e) => return e,
+            Error::Protocol(_) => ErrorKind::InvalidData,
+            Error::Aborted => ErrorKind::ConnectionAborted,
+            Error::Refused { reason, .. } => reason.kind(),
+            Error::Device { errno, .. } => errno::to_error_kind(errno),
+        };
+        std::io::Error::new(kind, e)
+    }
+}
+
 fn strerror(s: &'static str) -> std::io::Result<()> {
-    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, s))
+    Err(Error::Protocol(s).into())
 }
 
-// based on https://doc.rust-lang.org/src/std/io/util.rs.html#48
-fn mycopy<R: ?Sized, W: ?Sized, UWO>(
-    reader: &mut R,
-    mut writer: &mut W,
-    buf: &mut [u8],
-    mut limit: usize,
-    before_first_write: UWO,
-) -> ::std::io::Result<u64>
-where
-    R: ::std::io::Read,
-    W: ::std::io::Write,
-    UWO: FnOnce(&mut /*dyn*/ ::std::io::Write) -> ::std::io::Result<()>,
-{
-    let mut before_first_write = Some(before_first_write);
-    let mut written = 0;
-    loop {
-        let to_read = buf.len().min(limit);
-        let len = match reader.read(&mut buf[0..to_read]) {
-            Ok(0) => return Ok(written),
-            Ok(len) => len,
-            Err(ref e) if e.kind() == ::std::io::ErrorKind::Interrupted => continue,
-            Err(e) => return Err(e),
+/// STARTTLS support, enabled by `tls` cargo feature.
+///
+/// See `server::negotiate_tls` and `client::negotiate_tls`. Only certificate-based TLS is
+/// available: rustls does not implement TLS-PSK, so peers configured with pre-shared keys
+/// (e.g. `nbd-server` or `qemu-nbd` with a PSK file) cannot connect.
+#[cfg(feature = "tls")]
+pub mod tls {
+    use rustls::{ConnectionCommon, SideData, StreamOwned};
+    use std::io::{Error, ErrorKind, Read, Result, Write};
+    use std::ops::{Deref, DerefMut};
+
+    /// Connection which might have been upgraded to TLS during negotiation
+    #[derive(Debug)]
+    pub enum Stream<C, IO: Read + Write> {
+        /// Client has not asked for TLS
+        Plain(IO),
+        /// Negotiation and transmission continue over TLS
+        Tls(Box<StreamOwned<C, IO>>),
+    }
+
+    /// Connection on server side
+    pub type ServerStream<IO> = Stream<rustls::ServerConnection, IO>;
+    /// Connection on client side
+    pub type ClientStream<IO> = Stream<rustls::ClientConnection, IO>;
+
+    impl<C, IO: Read + Write> Stream<C, IO> {
+        /// Check if TLS is active
+        pub fn is_tls(&self) -> bool {
+            match *self {
+                Stream::Plain(_) => false,
+                Stream::Tls(_) => true,
+            }
+        }
+    }
+
+    impl<C, S, IO> Read for Stream<C, IO>
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+        S: SideData,
+        IO: Read + Write,
+    {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            match *self {
+                Stream::Plain(ref mut x) => x.read(buf),
+                Stream::Tls(ref mut x) => x.read(buf),
+            }
+        }
+    }
+
+    impl<C, S, IO> Write for Stream<C, IO>
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+        S: SideData,
+        IO: Read + Write,
+    {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            match *self {
+                Stream::Plain(ref mut x) => x.write(buf),
+                Stream::Tls(ref mut x) => x.write(buf),
+            }
+        }
+        fn flush(&mut self) -> Result<()> {
+            match *self {
+                Stream::Plain(ref mut x) => x.flush(),
+                Stream::Tls(ref mut x) => x.flush(),
+            }
+        }
+    }
+
+    /// Perform TLS handshake right after NBD_OPT_STARTTLS, so that failures show up early
+    pub(crate) fn handshake<C, S, IO>(conn: C, sock: IO) -> Result<StreamOwned<C, IO>>
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+        S: SideData,
+        IO: Read + Write,
+    {
+        let mut s = StreamOwned::new(conn, sock);
+        while s.conn.is_handshaking() {
+            s.conn.complete_io(&mut s.sock)?;
+        }
+        Ok(s)
+    }
+
+    pub(crate) fn error(e: rustls::Error) -> Error {
+        Error::new(ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Error codes used in NBD replies and their mapping to and from `std::io::Error`.
+///
+/// Values are fixed by the protocol and do not depend on the host.
+pub mod errno {
+    use std::io::{Error, ErrorKind};
+
+    /// Operation not permitted
+    pub const EPERM: u32 = 1;
+    /// Input/output error
+    pub const EIO: u32 = 5;
+    /// Cannot allocate memory
+    pub const ENOMEM: u32 = 12;
+    /// Invalid argument
+    pub const EINVAL: u32 = 22;
+    /// No space left on device
+    pub const ENOSPC: u32 = 28;
+    /// Value too large
+    pub const EOVERFLOW: u32 = 75;
+    /// Operation not supported
+    pub const ENOTSUP: u32 = 95;
+    /// Server is in the process of being shut down
+    pub const ESHUTDOWN: u32 = 108;
+
+    /// Choose error code to report the failure to client.
+    ///
+    /// Errors received from another NBD server keep their code. Otherwise the code is derived
+    /// from `ErrorKind`, falling back to `EIO`.
+    pub fn from_io_error(e: &Error) -> u32 {
+        if let Some(&::Error::Device { errno, .. }) = ::Error::of(e) {
+            return sanitize(errno);
+        }
+        // Linux values are the same as NBD ones
+        #[cfg(target_os = "linux")]
+        match e.raw_os_error() {
+            Some(x) if x > 0 && sanitize(x as u32) == x as u32 => return x as u32,
+            _ => (),
+        }
+        match e.kind() {
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => EPERM,
+            ErrorKind::OutOfMemory => ENOMEM,
+            ErrorKind::InvalidInput => EINVAL,
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge => ENOSPC,
+            ErrorKind::Unsupported => ENOTSUP,
+            _ => EIO,
+        }
+    }
+
+    /// Kind of `std::io::Error` for an error code received from server
+    pub fn to_error_kind(errno: u32) -> ErrorKind {
+        match errno {
+            EPERM => ErrorKind::PermissionDenied,
+            ENOMEM => ErrorKind::OutOfMemory,
+            EINVAL | EOVERFLOW => ErrorKind::InvalidInput,
+            ENOSPC => ErrorKind::StorageFull,
+            ENOTSUP => ErrorKind::Unsupported,
+            ESHUTDOWN => ErrorKind::ConnectionAborted,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Replace codes outside of the protocol's set with `EIO`
+    pub fn sanitize(errno: u32) -> u32 {
+        match errno {
+            EPERM | EIO | ENOMEM | EINVAL | ENOSPC | EOVERFLOW | ENOTSUP | ESHUTDOWN => errno,
+            _ => EIO,
+        }
+    }
+}
+
+/// Items for implementing NBD server
+///
+/// Expose a `BlockDevice` over a Read+Write socket using standard protocol: agree on an export
+/// with `negotiate`, then `serve` it. Plain Read+Write+Seek data can still be served by
+/// wrapping it in `ReadWriteSeek`, or with the older `handshake` and `transmission` pair.
+pub mod server {
+
+    use super::consts::*;
+    use super::{errno, strerror};
+    use byteorder::{BigEndian as BE, ReadBytesExt, WriteBytesExt};
+    use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::mpsc::sync_channel;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Write oldstyle negotiation header: magic, export size, flags and reserved zeroes.
+    ///
+    /// Upper 16 bits of `flags` are handshake flags (none are defined for oldstyle),
+    /// lower 16 bits are transmission flags. Use `oldstyle` to derive them from an `Export`.
+    pub fn oldstyle_header<W: Write>(mut c: W, size: u64, flags: u32) -> Result<()> {
+        c.write_all(b"NBDMAGIC")?;
+        c.write_all(b"\x00\x00\x42\x02\x81\x86\x12\x53")?;
+        c.write_u64::<BE>(size)?;
+        c.write_u32::<BE>(flags)?;
+        c.write_all(&[0; 124])?;
+        c.flush()?;
+        Ok(())
+    }
+
+    fn reply<IO: Write + Read>(mut c: IO, clopt: u32, rtype: u32, data: &[u8]) -> Result<()> {
+        c.write_u64::<BE>(0x3e889045565a9)?;
+        c.write_u32::<BE>(clopt)?;
+        c.write_u32::<BE>(rtype)?;
+        c.write_u32::<BE>(data.len() as u32)?;
+        c.write_all(data)?;
+        c.flush()?;
+        Ok(())
+    }
+
+    pub use super::{BlockSize, Export, Extent, ListedExport, MetaContext};
+
+    fn export_flags<Data>(export: &Export<Data>) -> u16 {
+        let mut flags = NBD_FLAG_HAS_FLAGS;
+        if export.readonly {
+            flags |= NBD_FLAG_READ_ONLY
+        } else {
+            flags |= NBD_FLAG_SEND_FLUSH
+        };
+        if export.resizeable {
+            flags |= NBD_FLAG_SEND_RESIZE
+        };
+        if export.rotational {
+            flags |= NBD_FLAG_ROTATIONAL
+        };
+        if export.send_trim {
+            flags |= NBD_FLAG_SEND_TRIM
+        };
+        if export.send_write_zeroes {
+            flags |= NBD_FLAG_SEND_WRITE_ZEROES;
+            if export.send_fast_zero {
+                flags |= NBD_FLAG_SEND_FAST_ZERO
+            }
+        };
+        if export.send_fua {
+            flags |= NBD_FLAG_SEND_FUA
+        };
+        if export.send_cache {
+            flags |= NBD_FLAG_SEND_CACHE
+        };
+        flags
+    }
+
+    /// Parse NBD_OPT_INFO or NBD_OPT_GO payload into export name and list of requested infos
+    fn parse_info_request(mut opt: &[u8]) -> Option<(String, Vec<u16>)> {
+        let namelen = opt.read_u32::<BE>().ok()? as usize;
+        if namelen > opt.len() {
+            return None;
+        }
+        let name = std::str::from_utf8(&opt[..namelen]).ok()?.to_owned();
+        opt = &opt[namelen..];
+        let n = opt.read_u16::<BE>().ok()? as usize;
+        if opt.len() != n * 2 {
+            return None;
+        }
+        let mut infos = Vec::with_capacity(n);
+        for _ in 0..n {
+            infos.push(opt.read_u16::<BE>().ok()?);
+        }
+        Some((name, infos))
+    }
+
+    fn reply_info<IO: Write + Read, Data>(
+        mut c: IO,
+        clopt: u32,
+        name: &str,
+        infos: &[u16],
+        export: &Export<Data>,
+    ) -> Result<()> {
+        let mut r = vec![];
+        r.write_u16::<BE>(NBD_INFO_EXPORT)?;
+        r.write_u64::<BE>(export.size)?;
+        r.write_u16::<BE>(export_flags(export))?;
+        reply(&mut c, clopt, NBD_REP_INFO, &r)?;
+
+        if infos.contains(&NBD_INFO_NAME) {
+            let name = export.name.as_ref().map(|x| &x[..]).unwrap_or(name);
+            let mut r = vec![];
+            r.write_u16::<BE>(NBD_INFO_NAME)?;
+            r.write_all(name.as_bytes())?;
+            reply(&mut c, clopt, NBD_REP_INFO, &r)?;
+        }
+        if infos.contains(&NBD_INFO_DESCRIPTION) {
+            if let Some(ref description) = export.description {
+                let mut r = vec![];
+                r.write_u16::<BE>(NBD_INFO_DESCRIPTION)?;
+                r.write_all(description.as_bytes())?;
+                reply(&mut c, clopt, NBD_REP_INFO, &r)?;
+            }
+        }
+        if infos.contains(&NBD_INFO_BLOCK_SIZE) {
+            if let Some(bs) = export.block_size {
+                let mut r = vec![];
+                r.write_u16::<BE>(NBD_INFO_BLOCK_SIZE)?;
+                r.write_u32::<BE>(bs.minimum)?;
+                r.write_u32::<BE>(bs.preferred)?;
+                r.write_u32::<BE>(bs.maximum)?;
+                reply(&mut c, clopt, NBD_REP_INFO, &r)?;
+            }
+        }
+        reply(&mut c, clopt, NBD_REP_ACK, b"")
+    }
+
+    /// Additional settings for `negotiate`
+    #[derive(Debug, Clone, Default)]
+    pub struct NegotiationOptions {
+        /// Catalogue of exports to report to NBD_OPT_LIST.
+        /// If `None`, listing is refused with NBD_REP_ERR_POLICY.
+        pub list: Option<Vec<ListedExport>>,
+        /// Metadata contexts to offer in addition to `base:allocation`
+        pub meta_contexts: MetaContextRegistry,
+        /// Decides on NBD_CMD_RESIZE for `resizeable` exports. Allows everything by default.
+        pub resize_policy: ResizePolicy,
+        /// Trigger for stopping negotiation and transmission, shared by all the connections
+        /// it is cloned into
+        pub shutdown: ShutdownHandle,
+    }
+
+    /// Tells servers to stop gracefully.
+    ///
+    /// Negotiation then refuses options with NBD_REP_ERR_SHUTDOWN. Transmission flushes the data,
+    /// answers the request it has received (if any) with ESHUTDOWN and returns. Blocking reads
+    /// cannot be interrupted in a portable way, so to stop idle connections too, register
+    /// a hook closing the socket with `on_shutdown`.
+    #[derive(Debug, Clone, Default)]
+    pub struct ShutdownHandle(Arc<ShutdownState>);
+
+    /// Hooks registered with `ShutdownHandle::on_shutdown`, by id
+    type ShutdownHooks = Vec<(u64, Box<dyn FnOnce() + Send>)>;
+
+    #[derive(Default)]
+    struct ShutdownState {
+        flag: AtomicBool,
+        next_hook: AtomicU64,
+        hooks: Mutex<ShutdownHooks>,
+    }
+
+    impl ShutdownState {
+        fn hooks(&self) -> ::std::sync::MutexGuard<'_, ShutdownHooks> {
+            self.hooks.lock().unwrap_or_else(|e| e.into_inner())
+        }
+    }
+
+    impl ::std::fmt::Debug for ShutdownState {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            f.debug_struct("ShutdownState")
+                .field("flag", &self.flag)
+                .field("hooks", &self.hooks().len())
+                .finish()
+        }
+    }
+
+    impl ShutdownHandle {
+        /// Start shutting down, running the hooks registered with `on_shutdown`
+        pub fn shutdown(&self) {
+            let hooks = {
+                let mut hooks = self.0.hooks();
+                self.0.flag.store(true, Ordering::SeqCst);
+                ::std::mem::take(&mut *hooks)
+            };
+            for (_, hook) in hooks {
+                hook();
+            }
+        }
+
+        /// Check if `shutdown` was called
+        pub fn is_shutdown(&self) -> bool {
+            self.0.flag.load(Ordering::SeqCst)
+        }
+
+        /// Run `hook` on `shutdown`, or right away if it was already called. Usually closes
+        /// a connection, like `TcpStream::shutdown`, to wake up a server waiting for requests.
+        ///
+        /// The hook is unregistered when the returned guard is dropped.
+        pub fn on_shutdown<F: FnOnce() + Send + 'static>(&self, hook: F) -> ShutdownHook {
+            let id = self.0.next_hook.fetch_add(1, Ordering::SeqCst);
+            let mut hooks = self.0.hooks();
+            if self.is_shutdown() {
+                drop(hooks);
+                hook();
+            } else {
+                hooks.push((id, Box::new(hook)));
+            }
+            ShutdownHook {
+                state: self.0.clone(),
+                id,
+            }
+        }
+    }
+
+    /// Registration of a hook made by `ShutdownHandle::on_shutdown`, removes the hook on drop
+    #[derive(Debug)]
+    #[must_use = "hook is unregistered when dropped"]
+    pub struct ShutdownHook {
+        state: Arc<ShutdownState>,
+        id: u64,
+    }
+
+    impl Drop for ShutdownHook {
+        fn drop(&mut self) {
+            self.state.hooks().retain(|x| x.0 != self.id);
+        }
+    }
+
+    /// Callback deciding whether an export may be resized from old to new size.
+    /// Error returned from it is reported to client.
+    #[derive(Clone)]
+    pub struct ResizePolicy(Arc<dyn Fn(u64, u64) -> Result<()> + Send + Sync>);
+
+    impl ResizePolicy {
+        /// Wrap a callback taking old and new size
+        pub fn new<F: Fn(u64, u64) -> Result<()> + Send + Sync + 'static>(f: F) -> Self {
+            ResizePolicy(Arc::new(f))
+        }
+
+        /// Check if resizing from `old` to `new` size is allowed
+        pub fn check(&self, old: u64, new: u64) -> Result<()> {
+            (self.0)(old, new)
+        }
+    }
+
+    impl Default for ResizePolicy {
+        fn default() -> Self {
+            ResizePolicy::new(|_, _| Ok(()))
+        }
+    }
+
+    impl ::std::fmt::Debug for ResizePolicy {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            f.write_str("ResizePolicy")
+        }
+    }
+
+    /// Source of a custom metadata context for NBD_CMD_BLOCK_STATUS,
+    /// like `qemu:dirty-bitmap:backup0` or `qemu:allocation-depth`
+    pub trait MetaContextProvider: Send + Sync {
+        /// Full name of the context, `namespace:leaf`
+        fn name(&self) -> &str;
+
+        /// Describe the range starting from `offset` as a list of `(length, flags)` pairs.
+        /// Meaning of the flags is defined by the context.
+        /// The pairs may cover less than `length`, but not more.
+        fn block_status(&self, offset: u64, length: u64) -> Result<Vec<(u64, u32)>>;
+    }
+
+    /// Custom metadata contexts offered to clients during negotiation
+    #[derive(Clone, Default)]
+    pub struct MetaContextRegistry {
+        providers: Vec<Arc<dyn MetaContextProvider>>,
+    }
+
+    impl MetaContextRegistry {
+        /// Offer the context to clients, replacing a previously registered one with the same name
+        pub fn register(&mut self, provider: Arc<dyn MetaContextProvider>) {
+            self.providers.retain(|x| x.name() != provider.name());
+            self.providers.push(provider);
+        }
+
+        /// Find a registered context by its name
+        pub fn get(&self, name: &str) -> Option<&Arc<dyn MetaContextProvider>> {
+            self.providers.iter().find(|x| x.name() == name)
+        }
+    }
+
+    impl ::std::fmt::Debug for MetaContextRegistry {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+            f.debug_list()
+                .entries(self.providers.iter().map(|x| x.name()))
+                .finish()
+        }
+    }
+
+    /// Result of a successful `negotiate`
+    #[derive(Debug)]
+    pub struct Negotiated<Data> {
+        /// Name of the export, as requested by client
+        pub name: String,
+        /// The export returned by callback for that name
+        pub export: Export<Data>,
+        /// Things to be passed to `serve`
+        pub session: Session,
+    }
+
+    /// Options agreed upon during negotiation which affect transmission phase
+    #[derive(Debug, Clone, Default)]
+    pub struct Session {
+        /// Client has enabled structured replies with NBD_OPT_STRUCTURED_REPLY
+        pub structured_replies: bool,
+        /// Client has enabled 64-bit extended headers with NBD_OPT_EXTENDED_HEADERS.
+        /// Implies `structured_replies`.
+        pub extended_headers: bool,
+        /// Metadata contexts selected with NBD_OPT_SET_META_CONTEXT, to be reported by NBD_CMD_BLOCK_STATUS
+        pub meta_contexts: Vec<MetaContext>,
+        /// Providers for custom contexts among `meta_contexts`
+        pub custom_contexts: MetaContextRegistry,
+        /// Size of the selected export, requests past it are rejected.
+        /// If `None`, size reported by `BlockDevice::size` is used.
+        pub size: Option<u64>,
+        /// Block size constraints advertised for the selected export.
+        /// Unaligned requests and payloads above the maximum are rejected.
+        /// `None` if client has not been told about them, i.e. it has used NBD_OPT_EXPORT_NAME
+        /// or has not asked for NBD_INFO_BLOCK_SIZE in NBD_OPT_GO.
+        pub block_size: Option<BlockSize>,
+        /// NBD_CMD_RESIZE is refused unless this is set, i.e. the export is `resizeable`
+        pub resize_policy: Option<ResizePolicy>,
+        /// Copy of `NegotiationOptions::shutdown`
+        pub shutdown: ShutdownHandle,
+    }
+
+    impl Session {
+        /// Remember properties of the export selected by client.
+        /// Block size constraints are only enforced if they were `advertised`.
+        fn select_export<Data>(
+            &mut self,
+            export: &Export<Data>,
+            options: &NegotiationOptions,
+            advertised: bool,
+        ) {
+            self.size = Some(export.size);
+            self.block_size = if advertised { export.block_size } else { None };
+            self.resize_policy = if export.resizeable {
+                Some(options.resize_policy.clone())
+            } else {
+                None
+            };
+        }
+    }
+
+    /// Parse NBD_OPT_LIST_META_CONTEXT or NBD_OPT_SET_META_CONTEXT payload into export name and queries
+    fn parse_meta_context_request(mut opt: &[u8]) -> Option<(String, Vec<String>)> {
+        fn string(opt: &mut &[u8]) -> Option<String> {
+            let len = opt.read_u32::<BE>().ok()? as usize;
+            if len > opt.len() {
+                return None;
+            }
+            let (s, rest) = opt.split_at(len);
+            *opt = rest;
+            String::from_utf8(s.to_vec()).ok()
+        }
+        let name = string(&mut opt)?;
+        let n = opt.read_u32::<BE>().ok()?;
+        let mut queries = vec![];
+        for _ in 0..n {
+            queries.push(string(&mut opt)?);
+        }
+        if !opt.is_empty() {
+            return None;
+        }
+        Some((name, queries))
+    }
+
+    /// Answer NBD_OPT_LIST_META_CONTEXT or NBD_OPT_SET_META_CONTEXT, returning matching contexts.
+    ///
+    /// Listing also matches namespace queries like `base:` and reports all contexts
+    /// if there are no queries at all.
+    /// Context ids are positions in the registry, with `base:allocation` being 0.
+    fn reply_meta_contexts<IO: Write + Read>(
+        mut c: IO,
+        clopt: u32,
+        queries: &[String],
+        registry: &MetaContextRegistry,
+    ) -> Result<Vec<MetaContext>> {
+        let listing = clopt == NBD_OPT_LIST_META_CONTEXT;
+        let mut matched = vec![];
+        let custom = registry.providers.iter().map(|x| x.name());
+        for (id, name) in ::std::iter::once(BASE_ALLOCATION).chain(custom).enumerate() {
+            let selected = (listing && queries.is_empty())
+                || queries.iter().any(|q| {
+                    q == name || (listing && q.ends_with(':') && name.starts_with(&q[..]))
+                });
+            if !selected {
+                continue;
+            }
+            let id = if listing { 0 } else { id as u32 };
+            let mut r = vec![];
+            r.write_u32::<BE>(id)?;
+            r.write_all(name.as_bytes())?;
+            reply(&mut c, clopt, NBD_REP_META_CONTEXT, &r)?;
+            matched.push(MetaContext {
+                id,
+                name: name.to_string(),
+            });
+        }
+        reply(&mut c, clopt, NBD_REP_ACK, b"")?;
+        Ok(matched)
+    }
+
+    fn reply_list<IO: Write + Read>(mut c: IO, clopt: u32, list: &[ListedExport]) -> Result<()> {
+        for e in list {
+            let mut r = vec![];
+            r.write_u32::<BE>(e.name.len() as u32)?;
+            r.write_all(e.name.as_bytes())?;
+            if let Some(ref description) = e.description {
+                r.write_all(description.as_bytes())?;
+            }
+            reply(&mut c, clopt, NBD_REP_SERVER, &r)?;
+        }
+        reply(&mut c, clopt, NBD_REP_ACK, b"")
+    }
+
+    /// Passes the requested export name to the provided callback to get the requested export.
+    ///
+    /// The callback may be called multiple times, as client is allowed to query
+    /// exports with NBD_OPT_INFO before choosing one. Error from the callback is reported to client
+    /// as an unknown export (if the protocol allows that).
+    ///
+    /// Listing exports is refused with NBD_REP_ERR_POLICY. Earlier versions answered
+    /// NBD_OPT_LIST with a single fixed `rustnbd` entry, so e.g. `nbd-client -l` now gets an
+    /// error instead; use `handshake_with_list` to report a catalogue. Protocol extensions which would require cooperation from `transmission`
+    /// are refused; use `negotiate` to get those.
+    pub fn handshake<IO: Write + Read, Data, F: FnMut(&str) -> Result<Export<Data>>>(
+        c: IO,
+        exports: F,
+    ) -> Result<Data> {
+        let n = negotiate_impl(c, &NegotiationOptions::default(), exports, true)?;
+        Ok(n.export.data)
+    }
+
+    /// Like `handshake`, but answers NBD_OPT_LIST with given catalogue of exports.
+    pub fn handshake_with_list<IO, Data, F>(
+        c: IO,
+        list: &[ListedExport],
+        exports: F,
+    ) -> Result<Data>
+    where
+        IO: Write + Read,
+        F: FnMut(&str) -> Result<Export<Data>>,
+    {
+        let options = NegotiationOptions {
+            list: Some(list.to_vec()),
+            ..Default::default()
         };
-        if let Some(bfw) = before_first_write.take() {
-            bfw(&mut writer)?;
+        let n = negotiate_impl(c, &options, exports, true)?;
+        Ok(n.export.data)
+    }
+
+    /// Like `handshake`, but with additional options and returning more information.
+    ///
+    /// Serve the resulting export with `serve`, passing it `Negotiated::session`.
+    pub fn negotiate<IO: Write + Read, Data, F: FnMut(&str) -> Result<Export<Data>>>(
+        c: IO,
+        options: &NegotiationOptions,
+        exports: F,
+    ) -> Result<Negotiated<Data>> {
+        negotiate_impl(c, options, exports, false)
+    }
+
+    fn negotiate_impl<IO: Write + Read, Data, F: FnMut(&str) -> Result<Export<Data>>>(
+        mut c: IO,
+        options: &NegotiationOptions,
+        mut exports: F,
+        legacy: bool,
+    ) -> Result<Negotiated<Data>> {
+        let client_flags = greet(&mut c)?;
+        let tls = Tls::Unavailable;
+        match negotiate_options(c, options, &mut exports, legacy, client_flags, tls)? {
+            Options::Done(n) => Ok(*n),
+            Options::StartTls => unreachable!(),
+        }
+    }
+
+    /// TLS settings for `negotiate_tls`
+    #[cfg(feature = "tls")]
+    #[derive(Debug, Clone)]
+    pub struct TlsOptions {
+        /// Certificate and key, client authentication and other TLS parameters.
+        /// Must use certificates, as rustls does not support TLS-PSK.
+        pub config: Arc<::rustls::ServerConfig>,
+        /// Refuse to negotiate anything before NBD_OPT_STARTTLS with NBD_REP_ERR_TLS_REQD
+        pub required: bool,
+    }
+
+    /// Like `negotiate`, but allows client to upgrade the connection with NBD_OPT_STARTTLS.
+    ///
+    /// Serve the export over the returned stream, which is TLS one if client has chosen so.
+    #[cfg(feature = "tls")]
+    pub fn negotiate_tls<IO, Data, F>(
+        mut c: IO,
+        options: &NegotiationOptions,
+        tls: &TlsOptions,
+        mut exports: F,
+    ) -> Result<(Negotiated<Data>, ::tls::ServerStream<IO>)>
+    where
+        IO: Write + Read,
+        F: FnMut(&str) -> Result<Export<Data>>,
+    {
+        let client_flags = greet(&mut c)?;
+        let offered = Tls::Offered {
+            required: tls.required,
+        };
+        match negotiate_options(&mut c, options, &mut exports, false, client_flags, offered)? {
+            Options::Done(n) => return Ok((*n, ::tls::Stream::Plain(c))),
+            Options::StartTls => (),
+        }
+        let conn = ::rustls::ServerConnection::new(tls.config.clone()).map_err(::tls::error)?;
+        let mut s = ::tls::handshake(conn, c)?;
+        match negotiate_options(
+            &mut s,
+            options,
+            &mut exports,
+            false,
+            client_flags,
+            Tls::Active,
+        )? {
+            Options::Done(n) => Ok((*n, ::tls::Stream::Tls(Box::new(s)))),
+            Options::StartTls => unreachable!(),
+        }
+    }
+
+    /// Send the initial greeting and return flags from client
+    fn greet<IO: Write + Read>(mut c: IO) -> Result<u32> {
+        //let hs_flags = NBD_FLAG_FIXED_NEWSTYLE;
+        let hs_flags = NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES;
+
+        c.write_all(b"NBDMAGIC")?;
+        c.write_all(b"IHAVEOPT")?;
+        c.write_u16::<BE>(hs_flags)?;
+        c.flush()?;
+
+        let client_flags = c.read_u32::<BE>()?;
+
+        // Plain newstyle clients (without NBD_FLAG_C_FIXED_NEWSTYLE) are accepted as well
+        if client_flags & !(NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES) != 0 {
+            strerror("Unknown client flags")?;
+        }
+        Ok(client_flags)
+    }
+
+    /// Availability of NBD_OPT_STARTTLS
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    enum Tls {
+        Unavailable,
+        /// May be started. If `required`, other options are refused until that.
+        Offered {
+            required: bool,
+        },
+        Active,
+    }
+
+    /// How the option phase has ended
+    enum Options<Data> {
+        Done(Box<Negotiated<Data>>),
+        /// NBD_OPT_STARTTLS is acknowledged, the rest of negotiation goes over TLS
+        StartTls,
+    }
+
+    fn negotiate_options<IO, Data, F>(
+        mut c: IO,
+        options: &NegotiationOptions,
+        exports: &mut F,
+        legacy: bool,
+        client_flags: u32,
+        tls: Tls,
+    ) -> Result<Options<Data>>
+    where
+        IO: Write + Read,
+        F: FnMut(&str) -> Result<Export<Data>>,
+    {
+        let mut session = Session {
+            shutdown: options.shutdown.clone(),
+            ..Default::default()
+        };
+        // export for which metadata contexts were selected
+        let mut meta_contexts_for = String::new();
+        // plain newstyle does not allow to reply with an error
+        let fixed = client_flags & NBD_FLAG_C_FIXED_NEWSTYLE != 0;
+
+        loop {
+            let client_optmagic = c.read_u64::<BE>()?;
+
+            if client_optmagic != 0x49484156454F5054 {
+                // IHAVEOPT
+                strerror("Invalid client optmagic")?;
+            }
+
+            let clopt = c.read_u32::<BE>()?;
+            let optlen = c.read_u32::<BE>()?;
+
+            if optlen > 100000 {
+                if clopt == NBD_OPT_EXPORT_NAME || !fixed {
+                    // There is no way to refuse it
+                    strerror("Option is too long")?;
+                }
+                let skipped =
+                    ::std::io::copy(&mut (&mut c).take(optlen.into()), &mut ::std::io::sink())?;
+                if skipped != u64::from(optlen) {
+                    strerror("Unexpected end of option")?;
+                }
+                reply(&mut c, clopt, NBD_REP_ERR_TOO_BIG, b"Option is too long")?;
+                continue;
+            }
+
+            let mut opt = vec![0; optlen as usize];
+            c.read_exact(&mut opt)?;
+
+            if options.shutdown.is_shutdown() && clopt != NBD_OPT_ABORT {
+                if clopt == NBD_OPT_EXPORT_NAME {
+                    // There is no way to refuse it
+                    return Err(shutdown_error());
+                }
+                reply(
+                    &mut c,
+                    clopt,
+                    NBD_REP_ERR_SHUTDOWN,
+                    b"Server is shutting down",
+                )?;
+                continue;
+            }
+
+            let tls_required = tls == Tls::Offered { required: true };
+            if tls_required && clopt != NBD_OPT_STARTTLS && clopt != NBD_OPT_ABORT {
+                if clopt == NBD_OPT_EXPORT_NAME {
+                    strerror("Client has not started required TLS")?;
+                }
+                reply(&mut c, clopt, NBD_REP_ERR_TLS_REQD, b"TLS is required")?;
+                continue;
+            }
+
+            match clopt {
+                NBD_OPT_EXPORT_NAME => {
+                    let export_name = std::str::from_utf8(&opt)
+                        .map_err(|_| strerror("Non-UTF8 export name requested").unwrap_err())?;
+                    let export = exports(export_name)?;
+                    if export_name != meta_contexts_for {
+                        session.meta_contexts.clear();
+                    }
+                    session.select_export(&export, options, false);
+                    c.write_u64::<BE>(export.size)?;
+                    c.write_u16::<BE>(export_flags(&export))?;
+                    if client_flags & NBD_FLAG_C_NO_ZEROES == 0 {
+                        c.write_all(&[0; 124])?;
+                    }
+                    c.flush()?;
+                    return Ok(Options::Done(Box::new(Negotiated {
+                        name: export_name.to_owned(),
+                        export,
+                        session,
+                    })));
+                }
+                NBD_OPT_ABORT => {
+                    reply(&mut c, clopt, NBD_REP_ACK, b"")?;
+                    return Err(super::Error::Aborted.into());
+                }
+                NBD_OPT_LIST => {
+                    if optlen != 0 {
+                        if !fixed {
+                            strerror("NBD_OPT_LIST with content")?;
+                        }
+                        reply(&mut c, clopt, NBD_REP_ERR_INVALID, b"")?;
+                    } else if let Some(ref list) = options.list {
+                        reply_list(&mut c, clopt, list)?;
+                    } else {
+                        reply(&mut c, clopt, NBD_REP_ERR_POLICY, b"Listing is disabled")?;
+                    }
+                }
+                NBD_OPT_STARTTLS => match tls {
+                    Tls::Offered { .. } if optlen == 0 => {
+                        reply(&mut c, clopt, NBD_REP_ACK, b"")?;
+                        return Ok(Options::StartTls);
+                    }
+                    Tls::Offered { .. } => reply(&mut c, clopt, NBD_REP_ERR_INVALID, b"")?,
+                    Tls::Active => {
+                        reply(&mut c, clopt, NBD_REP_ERR_INVALID, b"TLS is already active")?
+                    }
+                    Tls::Unavailable => {
+                        reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"TLS is not supported")?
+                    }
+                },
+                NBD_OPT_INFO | NBD_OPT_GO => {
+                    let (name, infos) = match parse_info_request(&opt) {
+                        Some(x) => x,
+                        None => {
+                            reply(&mut c, clopt, NBD_REP_ERR_INVALID, b"")?;
+                            continue;
+                        }
+                    };
+                    let export = match exports(&name) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            let msg = e.to_string();
+                            reply(&mut c, clopt, NBD_REP_ERR_UNKNOWN, msg.as_bytes())?;
+                            continue;
+                        }
+                    };
+                    reply_info(&mut c, clopt, &name, &infos, &export)?;
+                    if clopt == NBD_OPT_GO {
+                        if name != meta_contexts_for {
+                            session.meta_contexts.clear();
+                        }
+                        let advertised = infos.contains(&NBD_INFO_BLOCK_SIZE);
+                        session.select_export(&export, options, advertised);
+                        return Ok(Options::Done(Box::new(Negotiated {
+                            name,
+                            export,
+                            session,
+                        })));
+                    }
+                }
+                NBD_OPT_STRUCTURED_REPLY => {
+                    if legacy {
+                        reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"")?;
+                    } else if optlen != 0 {
+                        reply(&mut c, clopt, NBD_REP_ERR_INVALID, b"")?;
+                    } else {
+                        session.structured_replies = true;
+                        reply(&mut c, clopt, NBD_REP_ACK, b"")?;
+                    }
+                }
+                NBD_OPT_EXTENDED_HEADERS => {
+                    if legacy {
+                        reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"")?;
+                    } else if optlen != 0 {
+                        reply(&mut c, clopt, NBD_REP_ERR_INVALID, b"")?;
+                    } else {
+                        session.extended_headers = true;
+                        session.structured_replies = true;
+                        reply(&mut c, clopt, NBD_REP_ACK, b"")?;
+                    }
+                }
+                NBD_OPT_LIST_META_CONTEXT | NBD_OPT_SET_META_CONTEXT => {
+                    if legacy {
+                        reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"")?;
+                        continue;
+                    }
+                    if !session.structured_replies {
+                        let msg = b"Structured replies are not negotiated";
+                        reply(&mut c, clopt, NBD_REP_ERR_INVALID, msg)?;
+                        continue;
+                    }
+                    let (name, queries) = match parse_meta_context_request(&opt) {
+                        Some(x) => x,
+                        None => {
+                            reply(&mut c, clopt, NBD_REP_ERR_INVALID, b"")?;
+                            continue;
+                        }
+                    };
+                    let registry = &options.meta_contexts;
+                    let matched = reply_meta_contexts(&mut c, clopt, &queries, registry)?;
+                    if clopt == NBD_OPT_SET_META_CONTEXT {
+                        session.meta_contexts = matched;
+                        session.custom_contexts = registry.clone();
+                        meta_contexts_for = name;
+                    }
+                }
+                _ => {
+                    if !fixed {
+                        strerror("Invalid client option type")?;
+                    }
+                    reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"")?;
+                }
+            }
+        }
+    }
+
+    /// Header of a request from client
+    struct Request {
+        flags: u16,
+        typ: u16,
+        handle: u64,
+        offset: u64,
+        length: u64,
+    }
+
+    fn read_request<IO: Read>(mut c: IO, session: &Session) -> Result<Request> {
+        let magic = c.read_u32::<BE>()?;
+        let expected_magic = if session.extended_headers {
+            0x21e41c71
+        } else {
+            0x25609513
+        };
+        if magic != expected_magic {
+            strerror("Invalid request magic")?;
+        }
+        let flags = c.read_u16::<BE>()?;
+        let typ = c.read_u16::<BE>()?;
+        let handle = c.read_u64::<BE>()?;
+        let offset = c.read_u64::<BE>()?;
+        let length = if session.extended_headers {
+            c.read_u64::<BE>()?
+        } else {
+            c.read_u32::<BE>()? as u64
+        };
+        Ok(Request {
+            flags,
+            typ,
+            handle,
+            offset,
+            length,
+        })
+    }
+
+    fn replyt<IO: Write>(mut c: IO, session: &Session, req: &Request, error: u32) -> Result<()> {
+        if session.extended_headers {
+            // There are no simple replies with extended headers
+            if error == 0 {
+                return reply_chunk(
+                    &mut c,
+                    session,
+                    req,
+                    NBD_REPLY_FLAG_DONE,
+                    NBD_REPLY_TYPE_NONE,
+                    b"",
+                );
+            } else {
+                return reply_error_chunk(&mut c, session, req, error, "", None);
+            }
+        }
+        c.write_u32::<BE>(0x67446698)?;
+        c.write_u32::<BE>(error)?;
+        c.write_u64::<BE>(req.handle)?;
+        Ok(())
+    }
+
+    fn replyte<IO: Write>(mut c: IO, session: &Session, req: &Request, error: Error) -> Result<()> {
+        if session.extended_headers {
+            let msg = error.to_string();
+            return reply_error_chunk(
+                &mut c,
+                session,
+                req,
+                errno::from_io_error(&error),
+                &msg,
+                None,
+            );
+        }
+        replyt(&mut c, session, req, errno::from_io_error(&error))
+    }
+
+    /// Write header of a structured reply chunk, extended one if negotiated
+    fn chunk_header<IO: Write>(
+        mut c: IO,
+        session: &Session,
+        req: &Request,
+        flags: u16,
+        typ: u16,
+        length: u64,
+    ) -> Result<()> {
+        if session.extended_headers {
+            c.write_u32::<BE>(0x6e8a278c)?;
+            c.write_u16::<BE>(flags)?;
+            c.write_u16::<BE>(typ)?;
+            c.write_u64::<BE>(req.handle)?;
+            c.write_u64::<BE>(req.offset)?;
+            c.write_u64::<BE>(length)?;
+        } else {
+            c.write_u32::<BE>(0x668e33ef)?;
+            c.write_u16::<BE>(flags)?;
+            c.write_u16::<BE>(typ)?;
+            c.write_u64::<BE>(req.handle)?;
+            c.write_u32::<BE>(length as u32)?;
+        }
+        Ok(())
+    }
+
+    fn reply_chunk<IO: Write>(
+        mut c: IO,
+        session: &Session,
+        req: &Request,
+        flags: u16,
+        typ: u16,
+        payload: &[u8],
+    ) -> Result<()> {
+        chunk_header(&mut c, session, req, flags, typ, payload.len() as u64)?;
+        c.write_all(payload)?;
+        Ok(())
+    }
+
+    /// Send NBD_REPLY_TYPE_ERROR (or NBD_REPLY_TYPE_ERROR_OFFSET if `offset` is specified)
+    /// as the final chunk.
+    fn reply_error_chunk<IO: Write>(
+        mut c: IO,
+        session: &Session,
+        req: &Request,
+        error: u32,
+        msg: &str,
+        offset: Option<u64>,
+    ) -> Result<()> {
+        let mut msglen = msg.len().min(4096);
+        while !msg.is_char_boundary(msglen) {
+            msglen -= 1;
+        }
+        let mut r = vec![];
+        r.write_u32::<BE>(error)?;
+        r.write_u16::<BE>(msglen as u16)?;
+        r.write_all(&msg.as_bytes()[..msglen])?;
+        let typ = if let Some(offset) = offset {
+            r.write_u64::<BE>(offset)?;
+            NBD_REPLY_TYPE_ERROR_OFFSET
+        } else {
+            NBD_REPLY_TYPE_ERROR
+        };
+        reply_chunk(&mut c, session, req, NBD_REPLY_FLAG_DONE, typ, &r)
+    }
+
+    /// Serve NBD_CMD_READ using structured reply chunks. Chunks consisting only
+    /// of zero bytes are sent as holes. Reading errors are reported without
+    /// breaking the connection.
+    fn structured_read<IO, D>(
+        mut c: IO,
+        mut data: D,
+        buf: &mut [u8],
+        session: &Session,
+        req: &Request,
+    ) -> Result<()>
+    where
+        IO: Write,
+        D: BlockDevice,
+    {
+        if req.length == 0 {
+            return reply_chunk(
+                &mut c,
+                session,
+                req,
+                NBD_REPLY_FLAG_DONE,
+                NBD_REPLY_TYPE_NONE,
+                b"",
+            );
+        }
+        let mut pos = req.offset;
+        let mut remaining = req.length;
+        while remaining > 0 {
+            let len = (remaining.min(buf.len() as u64)) as usize;
+            let chunk = &mut buf[..len];
+            if let Err(e) = data.read_at(chunk, pos) {
+                let msg = e.to_string();
+                return reply_error_chunk(
+                    &mut c,
+                    session,
+                    req,
+                    errno::from_io_error(&e),
+                    &msg,
+                    Some(pos),
+                );
+            }
+            remaining -= len as u64;
+            let flags = if remaining == 0 {
+                NBD_REPLY_FLAG_DONE
+            } else {
+                0
+            };
+
+            if chunk.iter().all(|x| *x == 0) {
+                chunk_header(&mut c, session, req, flags, NBD_REPLY_TYPE_OFFSET_HOLE, 12)?;
+                c.write_u64::<BE>(pos)?;
+                c.write_u32::<BE>(len as u32)?;
+            } else {
+                chunk_header(
+                    &mut c,
+                    session,
+                    req,
+                    flags,
+                    NBD_REPLY_TYPE_OFFSET_DATA,
+                    8 + len as u64,
+                )?;
+                c.write_u64::<BE>(pos)?;
+                c.write_all(chunk)?;
+            }
+            pos += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Serve NBD_CMD_BLOCK_STATUS, sending a chunk for each selected metadata context
+    fn block_status<IO, D>(mut c: IO, mut data: D, session: &Session, req: &Request) -> Result<()>
+    where
+        IO: Write,
+        D: BlockDevice,
+    {
+        if !session.structured_replies || session.meta_contexts.is_empty() {
+            return replyt(&mut c, session, req, errno::EINVAL);
+        }
+        for (i, ctx) in session.meta_contexts.iter().enumerate() {
+            let extents = if ctx.name == BASE_ALLOCATION {
+                data.block_status(req.offset, req.length).map(|x| {
+                    x.into_iter()
+                        .map(|e| {
+                            let mut flags = 0;
+                            if e.hole {
+                                flags |= NBD_STATE_HOLE;
+                            }
+                            if e.zero {
+                                flags |= NBD_STATE_ZERO;
+                            }
+                            (e.length, flags)
+                        })
+                        .collect()
+                })
+            } else if let Some(p) = session.custom_contexts.get(&ctx.name) {
+                p.block_status(req.offset, req.length)
+            } else {
+                Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "unknown metadata context",
+                ))
+            };
+            let extents = match extents {
+                Ok(x) => x,
+                Err(e) => {
+                    let msg = e.to_string();
+                    return reply_error_chunk(
+                        &mut c,
+                        session,
+                        req,
+                        errno::from_io_error(&e),
+                        &msg,
+                        None,
+                    );
+                }
+            };
+
+            // Make sure the extents fit in the requested range
+            let mut descriptors = vec![];
+            let mut remaining = req.length;
+            for (length, flags) in extents {
+                let length = length.min(remaining);
+                if length == 0 {
+                    continue;
+                }
+                descriptors.push((length, flags));
+                remaining -= length;
+                if req.flags & NBD_CMD_FLAG_REQ_ONE != 0 {
+                    break;
+                }
+            }
+            if descriptors.is_empty() {
+                let msg = "no extents in the range";
+                return reply_error_chunk(&mut c, session, req, errno::EINVAL, msg, None);
+            }
+
+            let flags = if i + 1 == session.meta_contexts.len() {
+                NBD_REPLY_FLAG_DONE
+            } else {
+                0
+            };
+            let mut r = vec![];
+            r.write_u32::<BE>(ctx.id)?;
+            if session.extended_headers {
+                r.write_u32::<BE>(descriptors.len() as u32)?;
+                for (length, status) in descriptors {
+                    r.write_u64::<BE>(length)?;
+                    r.write_u64::<BE>(status as u64)?;
+                }
+                reply_chunk(
+                    &mut c,
+                    session,
+                    req,
+                    flags,
+                    NBD_REPLY_TYPE_BLOCK_STATUS_EXT,
+                    &r,
+                )?;
+            } else {
+                for (length, status) in descriptors {
+                    r.write_u32::<BE>(length as u32)?;
+                    r.write_u32::<BE>(status)?;
+                }
+                reply_chunk(&mut c, session, req, flags, NBD_REPLY_TYPE_BLOCK_STATUS, &r)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Data to be served with `serve`.
+    ///
+    /// Only positional reading and writing are mandatory, other operations have
+    /// reasonable fallbacks. Wrap `Read + Write + Seek` types into `ReadWriteSeek`
+    /// to serve them.
+    pub trait BlockDevice {
+        /// Fill entire `buf` with data starting from `offset`
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()>;
+
+        /// Write entire `buf` starting from `offset`
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()>;
+
+        /// Commit written data to stable storage (NBD_CMD_FLUSH). Does nothing by default.
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        /// Discard the data in given range (NBD_CMD_TRIM), e.g. by punching a hole.
+        ///
+        /// Contents of the range is unspecified afterwards. As the protocol allows servers
+        /// to ignore discard requests, default implementation does nothing.
+        fn trim(&mut self, offset: u64, length: u64) -> Result<()> {
+            let _ = (offset, length);
+            Ok(())
+        }
+
+        /// Efficiently fill given range with zeroes (NBD_CMD_WRITE_ZEROES).
+        /// If `may_trim` is set, it is allowed to punch a hole instead, provided that it reads back as zeroes.
+        ///
+        /// Should fail with `ErrorKind::Unsupported` if there is no efficient way to do this.
+        /// The server then either writes zeroes with `write_at`, or fails the request
+        /// if client has asked for fast zeroing. This is what default implementation does.
+        fn write_zeroes(&mut self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            let _ = (offset, length, may_trim);
+            Err(Error::new(ErrorKind::Unsupported, "no efficient zeroing"))
+        }
+
+        /// Prepare the range for upcoming reads (NBD_CMD_CACHE), e.g. by prefetching it into page cache.
+        ///
+        /// Should fail with `ErrorKind::Unsupported` if there is no way to do this.
+        /// The server then reads the range and discards the data. This is what default implementation does.
+        fn cache(&mut self, offset: u64, length: u64) -> Result<()> {
+            let _ = (offset, length);
+            Err(Error::new(ErrorKind::Unsupported, "no prefetching"))
+        }
+
+        /// Describe allocation of the range starting from `offset` as a list of extents.
+        /// The extents may cover less than `length`, but not more.
+        ///
+        /// Default implementation reports the whole range as allocated data.
+        fn block_status(&mut self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            let _ = offset;
+            Ok(vec![Extent {
+                length,
+                hole: false,
+                zero: false,
+            }])
+        }
+
+        /// Current size of the device
+        fn size(&mut self) -> Result<u64> {
+            Err(Error::new(ErrorKind::Unsupported, "size is unknown"))
+        }
+
+        /// Change size of the device
+        fn resize(&mut self, size: u64) -> Result<()> {
+            let _ = size;
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "resizing is not supported",
+            ))
+        }
+    }
+
+    #[cfg(unix)]
+    fn file_read_at(f: &::std::fs::File, buf: &mut [u8], offset: u64) -> Result<()> {
+        ::std::os::unix::fs::FileExt::read_exact_at(f, buf, offset)
+    }
+
+    #[cfg(unix)]
+    fn file_write_at(f: &::std::fs::File, buf: &[u8], offset: u64) -> Result<()> {
+        ::std::os::unix::fs::FileExt::write_all_at(f, buf, offset)
+    }
+
+    #[cfg(not(unix))]
+    fn file_read_at(mut f: &::std::fs::File, buf: &mut [u8], offset: u64) -> Result<()> {
+        f.seek(SeekFrom::Start(offset))?;
+        f.read_exact(buf)
+    }
+
+    #[cfg(not(unix))]
+    fn file_write_at(mut f: &::std::fs::File, buf: &[u8], offset: u64) -> Result<()> {
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(buf)
+    }
+
+    /// Punch a hole in the file
+    #[cfg(target_os = "linux")]
+    fn file_trim(f: &::std::fs::File, offset: u64, length: u64) -> Result<()> {
+        use rustix::fs::{fallocate, FallocateFlags};
+        let mode = FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE;
+        match fallocate(f, mode, offset, length) {
+            Ok(()) => Ok(()),
+            // Filesystem does not support holes, so just ignore the request
+            Err(rustix::io::Errno::OPNOTSUPP) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn file_trim(_f: &::std::fs::File, _offset: u64, _length: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Ask kernel to read ahead the range
+    #[cfg(target_os = "linux")]
+    fn file_cache(f: &::std::fs::File, offset: u64, length: u64) -> Result<()> {
+        use rustix::fs::{fadvise, Advice};
+        let length = ::std::num::NonZeroU64::new(length);
+        if length.is_none() {
+            return Ok(());
+        }
+        Ok(fadvise(f, offset, length, Advice::WillNeed)?)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn file_cache(_f: &::std::fs::File, _offset: u64, _length: u64) -> Result<()> {
+        Err(Error::new(ErrorKind::Unsupported, "no prefetching"))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn file_write_zeroes(
+        f: &::std::fs::File,
+        offset: u64,
+        length: u64,
+        may_trim: bool,
+    ) -> Result<()> {
+        use rustix::fs::{fallocate, FallocateFlags};
+        let mode = if may_trim {
+            FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE
+        } else {
+            FallocateFlags::ZERO_RANGE | FallocateFlags::KEEP_SIZE
+        };
+        match fallocate(f, mode, offset, length) {
+            Ok(()) => Ok(()),
+            Err(rustix::io::Errno::OPNOTSUPP) => {
+                Err(Error::new(ErrorKind::Unsupported, "no efficient zeroing"))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn file_write_zeroes(_f: &::std::fs::File, _o: u64, _l: u64, _may_trim: bool) -> Result<()> {
+        Err(Error::new(ErrorKind::Unsupported, "no efficient zeroing"))
+    }
+
+    /// Find data and holes with SEEK_DATA and SEEK_HOLE
+    #[cfg(target_os = "linux")]
+    fn file_block_status(f: &::std::fs::File, offset: u64, length: u64) -> Result<Vec<Extent>> {
+        use rustix::fs::{seek, SeekFrom};
+        let end = offset.saturating_add(length);
+        let mut pos = offset;
+        let mut extents = vec![];
+        let mut push = |length: u64, hole: bool| {
+            extents.push(Extent {
+                length,
+                hole,
+                zero: hole,
+            })
+        };
+        while pos < end {
+            let data = match seek(f, SeekFrom::Data(pos)) {
+                Ok(x) => x,
+                // No data up to the end of file
+                Err(rustix::io::Errno::NXIO) => end,
+                // Filesystem cannot tell
+                Err(rustix::io::Errno::INVAL) if pos == offset => {
+                    return Ok(vec![Extent {
+                        length,
+                        hole: false,
+                        zero: false,
+                    }])
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if data > pos {
+                let next = data.min(end);
+                push(next - pos, true);
+                pos = next;
+                continue;
+            }
+            let hole = seek(f, SeekFrom::Hole(pos))?.min(end);
+            push(hole - pos, false);
+            pos = hole;
+        }
+        Ok(extents)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn file_block_status(_f: &::std::fs::File, _offset: u64, length: u64) -> Result<Vec<Extent>> {
+        Ok(vec![Extent {
+            length,
+            hole: false,
+            zero: false,
+        }])
+    }
+
+    /// Uses positional I/O and `sync_data` for flush.
+    /// On Linux, punches holes on trim and reports them in block status.
+    impl BlockDevice for ::std::fs::File {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+            file_read_at(self, buf, offset)
+        }
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+            file_write_at(self, buf, offset)
+        }
+        fn flush(&mut self) -> Result<()> {
+            self.sync_data()
+        }
+        fn trim(&mut self, offset: u64, length: u64) -> Result<()> {
+            file_trim(self, offset, length)
+        }
+        fn write_zeroes(&mut self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            file_write_zeroes(self, offset, length, may_trim)
+        }
+        fn cache(&mut self, offset: u64, length: u64) -> Result<()> {
+            file_cache(self, offset, length)
+        }
+        fn block_status(&mut self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            file_block_status(self, offset, length)
+        }
+        fn size(&mut self) -> Result<u64> {
+            Ok(self.metadata()?.len())
+        }
+        fn resize(&mut self, size: u64) -> Result<()> {
+            self.set_len(size)
+        }
+    }
+
+    /// Grows on writes past the end. Fills trimmed ranges with zeroes.
+    impl BlockDevice for Cursor<Vec<u8>> {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+            let v = self.get_ref();
+            let end = offset.saturating_add(buf.len() as u64);
+            if end > v.len() as u64 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "read past the end"));
+            }
+            buf.copy_from_slice(&v[(offset as usize)..(end as usize)]);
+            Ok(())
+        }
+
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+            let v = self.get_mut();
+            let end = offset.saturating_add(buf.len() as u64) as usize;
+            if end > v.len() {
+                v.resize(end, 0);
+            }
+            v[(offset as usize)..end].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn trim(&mut self, offset: u64, length: u64) -> Result<()> {
+            let v = self.get_mut();
+            let start = offset.min(v.len() as u64) as usize;
+            let end = offset.saturating_add(length).min(v.len() as u64) as usize;
+            for x in &mut v[start..end] {
+                *x = 0;
+            }
+            Ok(())
+        }
+
+        fn write_zeroes(&mut self, offset: u64, length: u64, _may_trim: bool) -> Result<()> {
+            let v = self.get_mut();
+            let end = offset.saturating_add(length) as usize;
+            if end > v.len() {
+                v.resize(end, 0);
+            }
+            for x in &mut v[(offset as usize)..end] {
+                *x = 0;
+            }
+            Ok(())
+        }
+        fn cache(&mut self, _offset: u64, _length: u64) -> Result<()> {
+            Ok(())
+        }
+
+        fn size(&mut self) -> Result<u64> {
+            Ok(self.get_ref().len() as u64)
+        }
+
+        /// Growing fails with `ErrorKind::StorageFull` if the memory cannot be allocated
+        fn resize(&mut self, size: u64) -> Result<()> {
+            let v = self.get_mut();
+            if size > usize::MAX as u64 {
+                return Err(Error::new(ErrorKind::StorageFull, "too large"));
+            }
+            let size = size as usize;
+            if size > v.len() && v.try_reserve_exact(size - v.len()).is_err() {
+                return Err(Error::new(ErrorKind::StorageFull, "out of memory"));
+            }
+            v.resize(size, 0);
+            Ok(())
+        }
+    }
+
+    impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+            (**self).read_at(buf, offset)
+        }
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+            (**self).write_at(buf, offset)
+        }
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+        fn trim(&mut self, offset: u64, length: u64) -> Result<()> {
+            (**self).trim(offset, length)
+        }
+        fn write_zeroes(&mut self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            (**self).write_zeroes(offset, length, may_trim)
+        }
+        fn cache(&mut self, offset: u64, length: u64) -> Result<()> {
+            (**self).cache(offset, length)
+        }
+        fn block_status(&mut self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            (**self).block_status(offset, length)
+        }
+        fn size(&mut self) -> Result<u64> {
+            (**self).size()
+        }
+        fn resize(&mut self, size: u64) -> Result<()> {
+            (**self).resize(size)
+        }
+    }
+
+    /// Adapter for serving arbitrary `Read + Write + Seek` using default `BlockDevice` methods
+    #[derive(Debug)]
+    pub struct ReadWriteSeek<D>(pub D);
+
+    impl<D: Read + Write + Seek> BlockDevice for ReadWriteSeek<D> {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+            self.0.seek(SeekFrom::Start(offset))?;
+            self.0.read_exact(buf)
+        }
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+            self.0.seek(SeekFrom::Start(offset))?;
+            self.0.write_all(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            self.0.flush()
+        }
+        fn size(&mut self) -> Result<u64> {
+            self.0.seek(SeekFrom::End(0))
+        }
+    }
+
+    /// Data to be served with `serve_concurrent`, accessed from several threads at once.
+    /// Methods have the same meaning as in `BlockDevice`.
+    ///
+    /// `Mutex` turns any `BlockDevice` into one, although without actual parallelism.
+    pub trait SharedBlockDevice: Sync {
+        /// Fill entire `buf` with data starting from `offset`
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()>;
+
+        /// Write entire `buf` starting from `offset`
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<()>;
+
+        /// Commit written data to stable storage
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+
+        /// Discard the data in given range
+        fn trim(&self, offset: u64, length: u64) -> Result<()> {
+            let _ = (offset, length);
+            Ok(())
+        }
+
+        /// Efficiently fill given range with zeroes
+        fn write_zeroes(&self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            let _ = (offset, length, may_trim);
+            Err(Error::new(ErrorKind::Unsupported, "no efficient zeroing"))
+        }
+
+        /// Prepare the range for upcoming reads
+        fn cache(&self, offset: u64, length: u64) -> Result<()> {
+            let _ = (offset, length);
+            Err(Error::new(ErrorKind::Unsupported, "no prefetching"))
+        }
+
+        /// Describe allocation of the range
+        fn block_status(&self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            let _ = offset;
+            Ok(vec![Extent {
+                length,
+                hole: false,
+                zero: false,
+            }])
+        }
+
+        /// Current size of the device
+        fn size(&self) -> Result<u64> {
+            Err(Error::new(ErrorKind::Unsupported, "size is unknown"))
+        }
+
+        /// Change size of the device
+        fn resize(&self, size: u64) -> Result<()> {
+            let _ = size;
+            Err(Error::new(
+                ErrorKind::Unsupported,
+                "resizing is not supported",
+            ))
+        }
+    }
+
+    /// Uses positional I/O, so requests really run in parallel
+    #[cfg(unix)]
+    impl SharedBlockDevice for ::std::fs::File {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+            file_read_at(self, buf, offset)
+        }
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+            file_write_at(self, buf, offset)
+        }
+        fn flush(&self) -> Result<()> {
+            self.sync_data()
+        }
+        fn trim(&self, offset: u64, length: u64) -> Result<()> {
+            file_trim(self, offset, length)
+        }
+        fn write_zeroes(&self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            file_write_zeroes(self, offset, length, may_trim)
+        }
+        fn cache(&self, offset: u64, length: u64) -> Result<()> {
+            file_cache(self, offset, length)
+        }
+        fn block_status(&self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            file_block_status(self, offset, length)
+        }
+        fn size(&self) -> Result<u64> {
+            Ok(self.metadata()?.len())
+        }
+        fn resize(&self, size: u64) -> Result<()> {
+            self.set_len(size)
+        }
+    }
+
+    /// Serializes all the requests
+    impl<D: BlockDevice + Send> SharedBlockDevice for Mutex<D> {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+            self.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .read_at(buf, offset)
+        }
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+            self.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .write_at(buf, offset)
+        }
+        fn flush(&self) -> Result<()> {
+            self.lock().unwrap_or_else(|e| e.into_inner()).flush()
+        }
+        fn trim(&self, offset: u64, length: u64) -> Result<()> {
+            self.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .trim(offset, length)
+        }
+        fn write_zeroes(&self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            self.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .write_zeroes(offset, length, may_trim)
+        }
+        fn cache(&self, offset: u64, length: u64) -> Result<()> {
+            self.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .cache(offset, length)
+        }
+        fn block_status(&self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            self.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .block_status(offset, length)
+        }
+        fn size(&self) -> Result<u64> {
+            self.lock().unwrap_or_else(|e| e.into_inner()).size()
+        }
+        fn resize(&self, size: u64) -> Result<()> {
+            self.lock().unwrap_or_else(|e| e.into_inner()).resize(size)
+        }
+    }
+
+    impl<D: SharedBlockDevice + ?Sized> BlockDevice for &D {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+            (**self).read_at(buf, offset)
+        }
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+            (**self).write_at(buf, offset)
+        }
+        fn flush(&mut self) -> Result<()> {
+            (**self).flush()
+        }
+        fn trim(&mut self, offset: u64, length: u64) -> Result<()> {
+            (**self).trim(offset, length)
+        }
+        fn write_zeroes(&mut self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            (**self).write_zeroes(offset, length, may_trim)
+        }
+        fn cache(&mut self, offset: u64, length: u64) -> Result<()> {
+            (**self).cache(offset, length)
+        }
+        fn block_status(&mut self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            (**self).block_status(offset, length)
+        }
+        fn size(&mut self) -> Result<u64> {
+            (**self).size()
+        }
+        fn resize(&mut self, size: u64) -> Result<()> {
+            (**self).resize(size)
+        }
+    }
+
+    /// Record of blocks changed since the last backup, for incremental backups.
+    ///
+    /// Bit per `granularity` bytes. Exported as `qemu:dirty-bitmap:<name>` metadata context
+    /// with NBD_STATE_DIRTY flag, so backup client can query which blocks to pull.
+    #[derive(Debug)]
+    pub struct DirtyBitmap {
+        context: String,
+        granularity: u64,
+        bits: Mutex<Vec<u8>>,
+    }
+
+    /// Start of a saved `DirtyBitmap`
+    const DIRTY_BITMAP_MAGIC: &[u8; 8] = b"NBDDIRTY";
+
+    impl DirtyBitmap {
+        /// Create empty bitmap. `granularity` is size of a tracked block in bytes, must not be zero.
+        pub fn new(name: &str, granularity: u64) -> Self {
+            assert!(granularity > 0);
+            DirtyBitmap {
+                context: format!("qemu:dirty-bitmap:{}", name),
+                granularity,
+                bits: Mutex::new(vec![]),
+            }
+        }
+
+        /// Restore a bitmap written by `save`
+        pub fn load<R: Read>(name: &str, mut r: R) -> Result<Self> {
+            let mut magic = [0; 8];
+            r.read_exact(&mut magic)?;
+            if magic != *DIRTY_BITMAP_MAGIC {
+                strerror("Not a saved dirty bitmap")?;
+            }
+            let granularity = r.read_u64::<BE>()?;
+            if granularity == 0 {
+                strerror("Invalid dirty bitmap granularity")?;
+            }
+            let mut bits = vec![];
+            r.read_to_end(&mut bits)?;
+            let b = DirtyBitmap::new(name, granularity);
+            *b.lock() = bits;
+            Ok(b)
+        }
+
+        /// Write the bitmap for later `load`, e.g. to survive server restarts
+        pub fn save<W: Write>(&self, mut w: W) -> Result<()> {
+            let bits = self.lock().clone();
+            w.write_all(DIRTY_BITMAP_MAGIC)?;
+            w.write_u64::<BE>(self.granularity)?;
+            w.write_all(&bits)?;
+            w.flush()
+        }
+
+        fn lock(&self) -> ::std::sync::MutexGuard<'_, Vec<u8>> {
+            self.bits.lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        /// Size of a tracked block in bytes
+        pub fn granularity(&self) -> u64 {
+            self.granularity
+        }
+
+        /// Mark blocks overlapping the range as changed
+        pub fn mark(&self, offset: u64, length: u64) {
+            if length == 0 {
+                return;
+            }
+            let first = offset / self.granularity;
+            let last = (offset + (length - 1)) / self.granularity;
+            let mut bits = self.lock();
+            if bits.len() as u64 <= last / 8 {
+                bits.resize(last as usize / 8 + 1, 0);
+            }
+            for i in first..=last {
+                bits[i as usize / 8] |= 1 << (i % 8);
+            }
+        }
+
+        /// Check if any block overlapping the range is changed
+        pub fn is_dirty(&self, offset: u64, length: u64) -> bool {
+            self.ranges(offset, length).iter().any(|x| x.1)
+        }
+
+        /// Changed ranges as `(offset, length)` pairs, aligned to `granularity`
+        pub fn dirty_ranges(&self) -> Vec<(u64, u64)> {
+            let end = self.lock().len() as u64 * 8 * self.granularity;
+            let mut offset = 0;
+            let mut v = vec![];
+            for (length, dirty) in self.ranges(0, end) {
+                if dirty {
+                    v.push((offset, length));
+                }
+                offset += length;
+            }
+            v
+        }
+
+        /// Describe the range as runs of `(length, dirty)`
+        fn ranges(&self, offset: u64, length: u64) -> Vec<(u64, bool)> {
+            let bits = self.lock();
+            let g = self.granularity;
+            let tracked_end = (bits.len() as u64 * 8).saturating_mul(g);
+            let end = offset.saturating_add(length);
+            let mut v: Vec<(u64, bool)> = vec![];
+            let mut pos = offset;
+            while pos < end {
+                let (next, dirty) = if pos >= tracked_end {
+                    (end, false)
+                } else {
+                    let i = pos / g;
+                    let next = (i + 1).saturating_mul(g).min(end);
+                    (next, bits[i as usize / 8] & (1 << (i % 8)) != 0)
+                };
+                match v.last_mut() {
+                    Some(x) if x.1 == dirty => x.0 += next - pos,
+                    _ => v.push((next - pos, dirty)),
+                }
+                pos = next;
+            }
+            v
+        }
+
+        /// Forget changes in blocks fully covered by the range
+        pub fn clear_range(&self, offset: u64, length: u64) {
+            let g = self.granularity;
+            let first = offset.div_ceil(g);
+            let last = offset.saturating_add(length) / g;
+            let mut bits = self.lock();
+            for i in first..last.min(bits.len() as u64 * 8) {
+                bits[i as usize / 8] &= !(1 << (i % 8));
+            }
+        }
+
+        /// Forget all changes
+        pub fn clear(&self) {
+            self.lock().clear();
+        }
+
+        /// Copy of the bitmap under another name, e.g. to be exported for a backup
+        /// while the original continues tracking
+        pub fn snapshot(&self, name: &str) -> DirtyBitmap {
+            let b = DirtyBitmap::new(name, self.granularity);
+            *b.lock() = self.lock().clone();
+            b
+        }
+
+        /// Like `snapshot`, but also atomically clears this bitmap,
+        /// so that no write is lost between the two
+        pub fn snapshot_and_clear(&self, name: &str) -> DirtyBitmap {
+            let b = DirtyBitmap::new(name, self.granularity);
+            *b.lock() = ::std::mem::take(&mut *self.lock());
+            b
+        }
+    }
+
+    impl MetaContextProvider for DirtyBitmap {
+        fn name(&self) -> &str {
+            &self.context
+        }
+        fn block_status(&self, offset: u64, length: u64) -> Result<Vec<(u64, u32)>> {
+            Ok(self
+                .ranges(offset, length)
+                .into_iter()
+                .map(|(l, dirty)| (l, if dirty { NBD_STATE_DIRTY } else { 0 }))
+                .collect())
+        }
+    }
+
+    /// Wrapper which marks every written, trimmed or zeroed range in a `DirtyBitmap`.
+    ///
+    /// Ranges are marked once the inner device is done (even if it failed), so a concurrent
+    /// `DirtyBitmap::snapshot_and_clear` cannot take the mark away before the data changes.
+    #[derive(Debug)]
+    pub struct DirtyTracking<D> {
+        /// Wrapped device
+        pub inner: D,
+        /// Where the changes are recorded. Register it in `NegotiationOptions::meta_contexts`
+        /// to expose it to clients.
+        pub bitmap: Arc<DirtyBitmap>,
+    }
+
+    impl<D: BlockDevice> BlockDevice for DirtyTracking<D> {
+        fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+            self.inner.read_at(buf, offset)
+        }
+        fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+            let ret = self.inner.write_at(buf, offset);
+            self.bitmap.mark(offset, buf.len() as u64);
+            ret
+        }
+        fn flush(&mut self) -> Result<()> {
+            self.inner.flush()
+        }
+        fn trim(&mut self, offset: u64, length: u64) -> Result<()> {
+            let ret = self.inner.trim(offset, length);
+            self.bitmap.mark(offset, length);
+            ret
+        }
+        fn write_zeroes(&mut self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            let ret = self.inner.write_zeroes(offset, length, may_trim);
+            self.bitmap.mark(offset, length);
+            ret
+        }
+        fn cache(&mut self, offset: u64, length: u64) -> Result<()> {
+            self.inner.cache(offset, length)
+        }
+        fn block_status(&mut self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            self.inner.block_status(offset, length)
+        }
+        fn size(&mut self) -> Result<u64> {
+            self.inner.size()
+        }
+        fn resize(&mut self, size: u64) -> Result<()> {
+            self.inner.resize(size)
+        }
+    }
+
+    impl<D: SharedBlockDevice> SharedBlockDevice for DirtyTracking<D> {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+            self.inner.read_at(buf, offset)
+        }
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+            let ret = self.inner.write_at(buf, offset);
+            self.bitmap.mark(offset, buf.len() as u64);
+            ret
+        }
+        fn flush(&self) -> Result<()> {
+            self.inner.flush()
+        }
+        fn trim(&self, offset: u64, length: u64) -> Result<()> {
+            let ret = self.inner.trim(offset, length);
+            self.bitmap.mark(offset, length);
+            ret
+        }
+        fn write_zeroes(&self, offset: u64, length: u64, may_trim: bool) -> Result<()> {
+            let ret = self.inner.write_zeroes(offset, length, may_trim);
+            self.bitmap.mark(offset, length);
+            ret
+        }
+        fn cache(&self, offset: u64, length: u64) -> Result<()> {
+            self.inner.cache(offset, length)
+        }
+        fn block_status(&self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+            self.inner.block_status(offset, length)
         }
-        writer.write_all(&buf[..len])?;
-        written += len as u64;
-        //eprintln!("written={} limit={} len={}", written, limit, len);
-        limit -= len;
-        if limit == 0 {
-            return Ok(written);
+        fn size(&self) -> Result<u64> {
+            self.inner.size()
+        }
+        fn resize(&self, size: u64) -> Result<()> {
+            self.inner.resize(size)
         }
     }
-}
 
-/// Items for implementing NBD server
-///
-/// "Serialize" your Read+Write+Seek into a Read+Write socket using standard protocol.
-pub mod server {
+    /// Request payload on one side, reply being built on the other
+    struct Exchange {
+        payload: Cursor<Vec<u8>>,
+        reply: Vec<u8>,
+    }
 
-    use super::consts::*;
-    use super::{mycopy, strerror};
-    use byteorder::{BigEndian as BE, ReadBytesExt, WriteBytesExt};
-    use std::io::{Error, Read, Result, Seek, SeekFrom, Write};
+    impl Read for Exchange {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.payload.read(buf)
+        }
+    }
+    impl Write for Exchange {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.reply.write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
 
-    #[doc(hidden)]
-    pub fn oldstyle_header<W: Write>(mut c: W, size: u64, flags: u32) -> Result<()> {
-        c.write_all(b"NBDMAGIC")?;
-        c.write_all(b"\x00\x00\x42\x02\x81\x86\x12\x53")?;
-        c.write_u64::<BE>(size)?;
-        c.write_u32::<BE>(flags)?;
-        c.write_all(&[0; 124])?;
-        c.flush()?;
+    /// Slow path of NBD_CMD_WRITE_ZEROES
+    fn fill_zeroes<D: BlockDevice>(
+        mut data: D,
+        buf: &mut [u8],
+        mut offset: u64,
+        mut length: u64,
+    ) -> Result<()> {
+        for x in buf.iter_mut() {
+            *x = 0;
+        }
+        while length > 0 {
+            let len = length.min(buf.len() as u64) as usize;
+            data.write_at(&buf[..len], offset)?;
+            offset += len as u64;
+            length -= len as u64;
+        }
         Ok(())
     }
 
-    fn reply<IO: Write + Read>(mut c: IO, clopt: u32, rtype: u32, data: &[u8]) -> Result<()> {
-        c.write_u64::<BE>(0x3e889045565a9)?;
-        c.write_u32::<BE>(clopt)?;
-        c.write_u32::<BE>(rtype)?;
-        c.write_u32::<BE>(data.len() as u32)?;
-        c.write_all(data)?;
-        c.flush()?;
+    /// Slow path of NBD_CMD_CACHE
+    fn read_ahead<D: BlockDevice>(
+        mut data: D,
+        buf: &mut [u8],
+        mut offset: u64,
+        mut length: u64,
+    ) -> Result<()> {
+        while length > 0 {
+            let len = length.min(buf.len() as u64) as usize;
+            data.read_at(&mut buf[..len], offset)?;
+            offset += len as u64;
+            length -= len as u64;
+        }
         Ok(())
     }
 
-    pub use super::Export;
-
-    /// Passes the requested export name to the provided callback to get the requested export
-    pub fn handshake<IO: Write + Read, Data, F: FnOnce(&str) -> Result<Export<Data>>>(
-        mut c: IO,
-        exports: F,
-    ) -> Result<Data> {
-        //let hs_flags = NBD_FLAG_FIXED_NEWSTYLE;
-        let hs_flags = NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES;
-
-        c.write_all(b"NBDMAGIC")?;
-        c.write_all(b"IHAVEOPT")?;
-        c.write_u16::<BE>(hs_flags)?;
-        c.flush()?;
-
-        let client_flags = c.read_u32::<BE>()?;
-
-        if client_flags != NBD_FLAG_C_FIXED_NEWSTYLE
-            && client_flags != (NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES)
-        {
-            strerror("Invalid client flag")?;
+    /// Serve the export to a client using oldstyle negotiation, which consists
+    /// only of the server sending export size and flags.
+    ///
+    /// For legacy clients and appliances which do not speak newstyle. There are no export
+    /// names, no protocol extensions and no way to tell the client about block size constraints,
+    /// so the latter are not enforced. `options` provide resize policy and shutdown handle.
+    pub fn oldstyle<IO, D>(mut c: IO, export: Export<D>, options: &NegotiationOptions) -> Result<()>
+    where
+        IO: Read + Write,
+        D: BlockDevice,
+    {
+        if options.shutdown.is_shutdown() {
+            return Err(shutdown_error());
         }
+        oldstyle_header(&mut c, export.size, u32::from(export_flags(&export)))?;
+        let mut session = Session {
+            shutdown: options.shutdown.clone(),
+            ..Default::default()
+        };
+        session.select_export(&export, options, false);
+        serve(c, export.data, &session)
+    }
 
-        loop {
-            let client_optmagic = c.read_u64::<BE>()?;
+    /// Serve given data. If readonly, use a dummy `Write` implementation.
+    ///
+    /// Should be used after `handshake`
+    pub fn transmission<IO, D>(c: IO, data: D) -> Result<()>
+    where
+        IO: Read + Write,
+        D: Read + Write + Seek,
+    {
+        serve(c, ReadWriteSeek(data), &Session::default())
+    }
 
-            if client_optmagic != 0x49484156454F5054 {
-                // IHAVEOPT
-                strerror("Invalid client optmagic")?;
+    /// Serve given data, using protocol extensions enabled during `negotiate`.
+    ///
+    /// After `ShutdownHandle::shutdown` the data is flushed, a request received meanwhile is
+    /// answered with ESHUTDOWN, and the function returns. A connection waiting for the next
+    /// request only notices that when woken up, e.g. by closing the socket in a hook
+    /// registered with `ShutdownHandle::on_shutdown`.
+    pub fn serve<IO, D>(mut c: IO, mut data: D, session: &Session) -> Result<()>
+    where
+        IO: Read + Write,
+        D: BlockDevice,
+    {
+        let mut buf = vec![0; 65536];
+        let size = AtomicU64::new(initial_size(&mut data, session));
+        loop {
+            if session.shutdown.is_shutdown() {
+                return data.flush();
             }
-
-            let clopt = c.read_u32::<BE>()?;
-            let optlen = c.read_u32::<BE>()?;
-
-            if optlen > 100000 {
-                strerror("Suspiciously big option length")?;
+            let req = match read_request(&mut c, session) {
+                // Connection may have been closed to wake us up
+                Err(_) if session.shutdown.is_shutdown() => return data.flush(),
+                x => x?,
+            };
+            if req.typ == NBD_CMD_DISC {
+                if session.shutdown.is_shutdown() {
+                    data.flush()?;
+                }
+                return Ok(());
+            }
+            if oversized_payload(session, &req) {
+                // Connection cannot continue without consuming the payload
+                let e = Error::new(ErrorKind::InvalidInput, "payload is too large");
+                replyte(&mut c, session, &req, e)?;
+                c.flush()?;
+                return strerror("Request is too large");
             }
+            if session.shutdown.is_shutdown() {
+                data.flush()?;
+                discard_payload(&mut c, &mut buf, &req)?;
+                replyte(&mut c, session, &req, shutdown_error())?;
+                c.flush()?;
+                return Ok(());
+            }
+            handle_request(&mut c, &mut data, &mut buf, session, &size, &req)?;
+            c.flush()?;
+        }
+    }
 
-            let mut opt = vec![0; optlen as usize];
-            c.read_exact(&mut opt)?;
+    /// Largest read or write accepted by `serve_concurrent`, as it keeps entire requests in memory.
+    /// Longer reads fail with EOVERFLOW, longer writes end the connection.
+    pub const MAX_CONCURRENT_REQUEST: u64 = 32 * 1024 * 1024;
 
-            match clopt {
-                NBD_OPT_EXPORT_NAME => {
-                    let export_name = std::str::from_utf8(&opt)
-                        .map_err(|_| strerror("Non-UTF8 export name requested").unwrap_err())?;
-                    let export = exports(export_name)?;
-                    c.write_u64::<BE>(export.size)?;
-                    let mut flags = NBD_FLAG_HAS_FLAGS;
-                    if export.readonly {
-                        flags |= NBD_FLAG_READ_ONLY
-                    } else {
-                        flags |= NBD_FLAG_SEND_FLUSH
-                    };
-                    if export.resizeable {
-                        flags |= NBD_FLAG_SEND_RESIZE
-                    };
-                    if export.rotational {
-                        flags |= NBD_FLAG_ROTATIONAL
-                    };
-                    if export.send_trim {
-                        flags |= NBD_FLAG_SEND_TRIM
-                    };
-                    c.write_u16::<BE>(flags)?;
-                    if client_flags & NBD_FLAG_C_NO_ZEROES == 0 {
-                        c.write_all(&[0; 124])?;
-                    }
-                    c.flush()?;
-                    return Ok(export.data);
-                }
-                NBD_OPT_ABORT => {
-                    reply(&mut c, clopt, NBD_REP_ACK, b"")?;
-                    strerror("Client abort")?;
-                }
-                NBD_OPT_LIST => {
-                    if optlen != 0 {
-                        strerror("NBD_OPT_LIST with content")?;
+    /// Serve given data like `serve`, but process up to `workers` requests at once.
+    ///
+    /// Requests are read ahead from `r` and dispatched to a pool of threads.
+    /// Replies are written to `w` in order of completion. `r` and `w` are usually
+    /// two handles of the same socket, e.g. `TcpStream` and its `try_clone`.
+    /// On shutdown, requests already dispatched are completed before the data is flushed.
+    pub fn serve_concurrent<R, W, D>(
+        mut r: R,
+        w: W,
+        data: &D,
+        session: &Session,
+        workers: usize,
+    ) -> Result<()>
+    where
+        R: Read,
+        W: Write + Send,
+        D: SharedBlockDevice + ?Sized,
+    {
+        let workers = workers.max(1);
+        let size = AtomicU64::new(initial_size(data, session));
+        let w = Mutex::new(w);
+        let failure: Mutex<Option<Error>> = Mutex::new(None);
+        let (tx, rx) = sync_channel::<(Request, Vec<u8>)>(workers * 2);
+        let rx = Mutex::new(rx);
+
+        let ret = thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    let mut buf = vec![0; 65536];
+                    loop {
+                        let job = rx.lock().unwrap().recv();
+                        let (req, payload) = match job {
+                            Ok(x) => x,
+                            Err(_) => return,
+                        };
+                        if failure.lock().unwrap().is_some() {
+                            continue;
+                        }
+                        let mut exchange = Exchange {
+                            payload: Cursor::new(payload),
+                            reply: vec![],
+                        };
+                        let ret =
+                            handle_request(&mut exchange, data, &mut buf, session, &size, &req)
+                                .and_then(|()| {
+                                    let mut w = w.lock().unwrap();
+                                    w.write_all(&exchange.reply)?;
+                                    w.flush()
+                                });
+                        if let Err(e) = ret {
+                            failure.lock().unwrap().get_or_insert(e);
+                        }
                     }
+                });
+            }
 
-                    reply(&mut c, clopt, NBD_REP_SERVER, b"\x00\x00\x00\x07rustnbd")?;
-                    reply(&mut c, clopt, NBD_REP_ACK, b"")?;
-                }
-                NBD_OPT_STARTTLS => {
-                    strerror("TLS not supported")?;
-                }
-                NBD_OPT_INFO => {
-                    reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"")?;
+            // moved here to be dropped on return, stopping the workers
+            let tx = tx;
+            loop {
+                if failure.lock().unwrap().is_some() || session.shutdown.is_shutdown() {
+                    return Ok(());
                 }
-                NBD_OPT_GO => {
-                    reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"")?;
+                let req = match read_request(&mut r, session) {
+                    // Connection may have been closed to wake us up
+                    Err(_) if session.shutdown.is_shutdown() => return Ok(()),
+                    x => x?,
+                };
+                match req.typ {
+                    NBD_CMD_DISC => return Ok(()),
+                    NBD_CMD_READ if req.length > MAX_CONCURRENT_REQUEST => {
+                        // Nothing to skip, so the connection can go on
+                        let e = super::Error::Device {
+                            errno: errno::EOVERFLOW,
+                            message: Some("read is too large".to_owned()),
+                        };
+                        let mut w = w.lock().unwrap();
+                        replyte(&mut *w, session, &req, e.into())?;
+                        w.flush()?;
+                        continue;
+                    }
+                    NBD_CMD_WRITE if req.length > MAX_CONCURRENT_REQUEST => {
+                        strerror("Request is too large")?
+                    }
+                    _ if oversized_payload(session, &req) => strerror("Request is too large")?,
+                    NBD_CMD_READ | NBD_CMD_WRITE | NBD_CMD_FLUSH | NBD_CMD_TRIM
+                    | NBD_CMD_WRITE_ZEROES | NBD_CMD_BLOCK_STATUS | NBD_CMD_RESIZE
+                    | NBD_CMD_CACHE => {}
+                    _ => strerror("Unknown command from client")?,
                 }
-                NBD_OPT_STRUCTURED_REPLY => {
-                    reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"")?;
+                let mut payload = vec![];
+                if req.typ == NBD_CMD_WRITE {
+                    payload.resize(req.length as usize, 0);
+                    r.read_exact(&mut payload)?;
                 }
-                NBD_OPT_EXTENDED_HEADERS => {
-                    reply(&mut c, clopt, NBD_REP_ERR_UNSUP, b"")?;
+                if session.shutdown.is_shutdown() {
+                    let mut w = w.lock().unwrap();
+                    replyte(&mut *w, session, &req, shutdown_error())?;
+                    w.flush()?;
+                    return Ok(());
                 }
-                _ => {
-                    strerror("Invalid client option type")?;
+                if tx.send((req, payload)).is_err() {
+                    return Ok(());
                 }
             }
+        });
+        // All the workers are finished at this point
+        if let Some(e) = failure.into_inner().unwrap() {
+            return Err(e);
         }
+        if ret.is_ok() && session.shutdown.is_shutdown() {
+            data.flush()?;
+        }
+        ret
     }
 
-    fn replyt<IO: Write>(mut c: IO, error: u32, handle: u64) -> Result<()> {
-        c.write_u32::<BE>(0x67446698)?;
-        c.write_u32::<BE>(error)?;
-        c.write_u64::<BE>(handle)?;
-        Ok(())
-    }
-
-    fn replyte<IO: Write>(mut c: IO, error: Error, handle: u64) -> Result<()> {
-        let ec = if let Some(x) = error.raw_os_error() {
-            if (x as u32) != 0 {
-                x as u32
-            } else {
-                5
-            }
-        } else {
-            5
-        };
-        replyt(&mut c, ec, handle)
+    /// Size of the export at the start of transmission phase, `u64::MAX` if unknown
+    fn initial_size<D: BlockDevice>(mut data: D, session: &Session) -> u64 {
+        match session.size {
+            Some(x) => x,
+            None => data.size().unwrap_or(u64::MAX),
+        }
     }
 
-    /// Serve given data. If readonly, use a dummy `Write` implementation.
-    ///
-    /// Should be used after `handshake`
-    pub fn transmission<IO, D>(mut c: IO, mut data: D) -> Result<()>
+    /// Process one request other than NBD_CMD_DISC. Payload is read from `c` and the reply is written to it.
+    /// `size` is the current size of the export.
+    fn handle_request<IO, D>(
+        mut c: IO,
+        mut data: D,
+        buf: &mut [u8],
+        session: &Session,
+        size: &AtomicU64,
+        req: &Request,
+    ) -> Result<()>
     where
         IO: Read + Write,
-        D: Read + Write + Seek,
+        D: BlockDevice,
     {
-        let mut buf = vec![0; 65536];
-        loop {
-            let magic = c.read_u32::<BE>()?;
-            if magic != 0x25609513 {
-                strerror("Invalid request magic")?;
+        //eprintln!("typ={} handle={} off={} len={}", req.typ, req.handle, req.offset, req.length);
+        if let Err(e) = validate_request(session, size.load(Ordering::SeqCst), req) {
+            discard_payload(&mut c, buf, req)?;
+            return replyte(&mut c, session, req, e);
+        }
+        match req.typ {
+            NBD_CMD_READ if session.structured_replies => {
+                structured_read(&mut c, &mut data, buf, session, req)?;
             }
-            let _flags = c.read_u16::<BE>()?;
-            let typ = c.read_u16::<BE>()?;
-            let handle = c.read_u64::<BE>()?;
-            let offset = c.read_u64::<BE>()?;
-            let length = c.read_u32::<BE>()?;
-
-            //eprintln!("typ={} handle={} off={} len={}", typ, handle, offset, length);
-            match typ {
-                NBD_CMD_READ => {
-                    if let Err(e) = data.seek(SeekFrom::Start(offset)) {
-                        replyte(&mut c, e, handle)?;
-                    } else {
-                        let mut writing_in_progress = false;
-                        let ret;
-                        {
-                            // a hello from old borrowck
-                            let on_first_chunk = |c: &mut /*dyn*/ Write| {
-                                replyt(c, 0, handle)?;
-                                writing_in_progress = true;
-                                Ok(())
-                            };
-                            ret = mycopy(
-                                &mut data,
-                                &mut c,
-                                &mut buf,
-                                length as usize,
-                                on_first_chunk,
-                            );
-                        }
-                        match ret {
-                            Err(e) => {
-                                if writing_in_progress {
-                                    // Reading errors after already copying first chunk
-                                    // cannot be really handled, so aborting the entire connection
-                                    return Err(e);
-                                } else {
-                                    // Errors in the very first chunk can be non-fatal
-                                    replyte(&mut c, e, handle)?
-                                }
-                            }
-                            Ok(x) if x == (length as u64) => {}
-                            Ok(_) => {
-                                strerror("sudden EOF")?;
-                            }
+            NBD_CMD_READ => {
+                let mut pos = req.offset;
+                let mut remaining = req.length;
+                while remaining > 0 {
+                    let len = remaining.min(buf.len() as u64) as usize;
+                    if let Err(e) = data.read_at(&mut buf[..len], pos) {
+                        if pos == req.offset {
+                            // Errors in the very first chunk can be non-fatal
+                            return replyte(&mut c, session, req, e);
                         }
+                        // Reading errors after already sending first chunk
+                        // cannot be really handled, so aborting the entire connection
+                        return Err(e);
                     }
-                }
-                NBD_CMD_WRITE => {
-                    if let Err(e) = data.seek(SeekFrom::Start(offset)) {
-                        replyte(&mut c, e, handle)?;
-                    } else {
-                        let ret = mycopy(&mut c, &mut data, &mut buf, length as usize, |_| Ok(()));
-                        match ret {
-                            Err(e) => replyte(&mut c, e, handle)?,
-                            Ok(x) if x == (length as u64) => {
-                                replyt(&mut c, 0, handle)?;
-                            }
-                            Ok(_) => {
-                                strerror("sudden EOF")?;
-                            }
-                        }
+                    if pos == req.offset {
+                        replyt(&mut c, session, req, 0)?;
                     }
+                    c.write_all(&buf[..len])?;
+                    pos += len as u64;
+                    remaining -= len as u64;
                 }
-                NBD_CMD_DISC => {
-                    return Ok(());
+                if req.length == 0 {
+                    replyt(&mut c, session, req, 0)?;
                 }
-                NBD_CMD_FLUSH => {
-                    data.flush()?;
-                    replyt(&mut c, 0, handle)?;
+            }
+            NBD_CMD_WRITE => {
+                let mut pos = req.offset;
+                let mut remaining = req.length;
+                let mut ret = Ok(());
+                while remaining > 0 {
+                    let len = remaining.min(buf.len() as u64) as usize;
+                    c.read_exact(&mut buf[..len])?;
+                    if ret.is_ok() {
+                        // Keep consuming the payload after failure
+                        ret = data.write_at(&buf[..len], pos);
+                    }
+                    pos += len as u64;
+                    remaining -= len as u64;
                 }
-                NBD_CMD_TRIM => {
-                    replyt(&mut c, 38, handle)?;
+                match ret.and_then(|()| honour_fua(&mut data, req)) {
+                    Ok(()) => replyt(&mut c, session, req, 0)?,
+                    Err(e) => replyte(&mut c, session, req, e)?,
                 }
-                NBD_CMD_WRITE_ZEROES => {
-                    replyt(&mut c, 38, handle)?;
+            }
+            NBD_CMD_FLUSH => match data.flush() {
+                Ok(()) => replyt(&mut c, session, req, 0)?,
+                Err(e) => replyte(&mut c, session, req, e)?,
+            },
+            NBD_CMD_TRIM => match data
+                .trim(req.offset, req.length)
+                .and_then(|()| honour_fua(&mut data, req))
+            {
+                Ok(()) => replyt(&mut c, session, req, 0)?,
+                Err(e) => replyte(&mut c, session, req, e)?,
+            },
+            NBD_CMD_WRITE_ZEROES => {
+                let may_trim = req.flags & NBD_CMD_FLAG_NO_HOLE == 0;
+                let fast_only = req.flags & NBD_CMD_FLAG_FAST_ZERO != 0;
+                let ret = match data.write_zeroes(req.offset, req.length, may_trim) {
+                    Err(ref e) if e.kind() == ErrorKind::Unsupported && !fast_only => {
+                        fill_zeroes(&mut data, buf, req.offset, req.length)
+                    }
+                    x => x,
+                };
+                match ret.and_then(|()| honour_fua(&mut data, req)) {
+                    Ok(()) => replyt(&mut c, session, req, 0)?,
+                    Err(e) => replyte(&mut c, session, req, e)?,
                 }
-                _ => strerror("Unknown command from client")?,
             }
-            c.flush()?;
+            NBD_CMD_BLOCK_STATUS => block_status(&mut c, &mut data, session, req)?,
+            NBD_CMD_CACHE => {
+                let ret = match data.cache(req.offset, req.length) {
+                    Err(ref e) if e.kind() == ErrorKind::Unsupported => {
+                        read_ahead(&mut data, buf, req.offset, req.length)
+                    }
+                    x => x,
+                };
+                match ret {
+                    Ok(()) => replyt(&mut c, session, req, 0)?,
+                    Err(e) => replyte(&mut c, session, req, e)?,
+                }
+            }
+            NBD_CMD_RESIZE => match resize(&mut data, session, size, req) {
+                Ok(()) => replyt(&mut c, session, req, 0)?,
+                Err(e) => replyte(&mut c, session, req, e)?,
+            },
+            _ => strerror("Unknown command from client")?,
+        }
+        Ok(())
+    }
+
+    /// Skip the payload of a request which is not going to be handled
+    fn discard_payload<IO: Read>(mut c: IO, buf: &mut [u8], req: &Request) -> Result<()> {
+        if req.typ != NBD_CMD_WRITE {
+            return Ok(());
+        }
+        let mut remaining = req.length;
+        while remaining > 0 {
+            let len = remaining.min(buf.len() as u64) as usize;
+            c.read_exact(&mut buf[..len])?;
+            remaining -= len as u64;
+        }
+        Ok(())
+    }
+
+    fn shutdown_error() -> Error {
+        super::Error::Device {
+            errno: errno::ESHUTDOWN,
+            message: Some("server is shutting down".to_owned()),
+        }
+        .into()
+    }
+
+    /// Check the request against export size and block size constraints
+    fn validate_request(session: &Session, size: u64, req: &Request) -> Result<()> {
+        match req.typ {
+            NBD_CMD_READ | NBD_CMD_WRITE | NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES | NBD_CMD_CACHE
+            | NBD_CMD_BLOCK_STATUS => (),
+            _ => return Ok(()),
+        }
+        let invalid = |msg| Err(Error::new(ErrorKind::InvalidInput, msg));
+        if let Some(bs) = session.block_size {
+            let min = u64::from(bs.minimum.max(1));
+            if req.offset % min != 0 || req.length % min != 0 {
+                return invalid("request is not aligned to the minimum block size");
+            }
+            let has_payload = req.typ == NBD_CMD_READ || req.typ == NBD_CMD_WRITE;
+            if has_payload && req.length > u64::from(bs.maximum) {
+                return invalid("request exceeds the maximum block size");
+            }
+        }
+        match req.offset.checked_add(req.length) {
+            Some(end) if end <= size => Ok(()),
+            _ if req.typ == NBD_CMD_WRITE || req.typ == NBD_CMD_WRITE_ZEROES => Err(Error::new(
+                ErrorKind::StorageFull,
+                "request is past the end of the export",
+            )),
+            _ => invalid("request is past the end of the export"),
+        }
+    }
+
+    /// Check if client is going to send a payload above the advertised maximum,
+    /// which is not even read then
+    fn oversized_payload(session: &Session, req: &Request) -> bool {
+        match session.block_size {
+            Some(bs) => req.typ == NBD_CMD_WRITE && req.length > u64::from(bs.maximum),
+            None => false,
+        }
+    }
+
+    /// Handle NBD_CMD_RESIZE, which carries the new size in its offset field
+    fn resize<D: BlockDevice>(
+        mut data: D,
+        session: &Session,
+        size: &AtomicU64,
+        req: &Request,
+    ) -> Result<()> {
+        let policy = match session.resize_policy {
+            Some(ref x) => x,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "export is not resizeable",
+                ))
+            }
+        };
+        let new_size = req.offset;
+        if req.length != 0 || new_size > i64::MAX as u64 {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid size"));
+        }
+        policy.check(size.load(Ordering::SeqCst), new_size)?;
+        data.resize(new_size)?;
+        size.store(new_size, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Flush the data if the request carries NBD_CMD_FLAG_FUA, so that it reaches stable
+    /// storage before the reply (for `File`, via `sync_data`)
+    fn honour_fua<D: BlockDevice>(mut data: D, req: &Request) -> Result<()> {
+        if req.flags & NBD_CMD_FLAG_FUA != 0 {
+            data.flush()
+        } else {
+            Ok(())
         }
     }
 
     /// Recommended port for NBD servers, especially with new handshake format.
-    /// There is some untested, doc-hidden old handshake support in this library.
+    /// Oldstyle servers (see `oldstyle`) traditionally used a separate port per export.
     pub const DEFAULT_TCP_PORT: u16 = 10809;
 } // mod server
 
@@ -330,9 +2749,11 @@ pub mod client {
     use super::consts::*;
     use super::{strerror, CheckedAddI64, ClampToU32};
     use byteorder::{BigEndian as BE, ReadBytesExt, WriteBytesExt};
+    use std::collections::{HashMap, VecDeque};
     use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
 
-    pub use super::Export;
+    use super::Refusal;
+    pub use super::{BlockSize, Export, Extent, ListedExport, MetaContext};
 
     fn fill_in_flags(export: &mut Export, flags: u16) {
         if flags & NBD_FLAG_HAS_FLAGS != 0 {
@@ -351,11 +2772,240 @@ pub mod client {
             if flags & NBD_FLAG_SEND_FLUSH != 0 {
                 export.send_flush = true;
             }
+            if flags & NBD_FLAG_SEND_WRITE_ZEROES != 0 {
+                export.send_write_zeroes = true;
+            }
+            if flags & NBD_FLAG_SEND_FAST_ZERO != 0 {
+                export.send_fast_zero = true;
+            }
+            if flags & NBD_FLAG_SEND_FUA != 0 {
+                export.send_fua = true;
+            }
+            if flags & NBD_FLAG_SEND_CACHE != 0 {
+                export.send_cache = true;
+            }
+        }
+    }
+
+    fn send_option<IO: Write>(mut c: IO, opt: u32, data: &[u8]) -> Result<()> {
+        c.write_u64::<BE>(0x49484156454F5054)?; // IHAVEOPT
+        c.write_u32::<BE>(opt)?;
+        c.write_u32::<BE>(data.len() as u32)?;
+        c.write_all(data)?;
+        c.flush()?;
+        Ok(())
+    }
+
+    /// Read one option reply, returning its type and payload
+    fn read_option_reply<IO: Read>(mut c: IO, opt: u32) -> Result<(u32, Vec<u8>)> {
+        let magic = c.read_u64::<BE>()?;
+        if magic != 0x3e889045565a9 {
+            strerror("Invalid option reply magic")?;
+        }
+        let replied_opt = c.read_u32::<BE>()?;
+        if replied_opt != opt {
+            strerror("Option reply is for unexpected option")?;
+        }
+        let rtype = c.read_u32::<BE>()?;
+        let len = c.read_u32::<BE>()?;
+        if len > 100000 {
+            strerror("Suspiciously big option reply length")?;
+        }
+        let mut data = vec![0; len as usize];
+        c.read_exact(&mut data)?;
+        Ok((rtype, data))
+    }
+
+    /// Convert NBD_REP_ERR_* reply into an `Error`
+    fn option_error(rtype: u32, msg: &[u8]) -> Error {
+        super::Error::Refused {
+            reason: Refusal::from_reply(rtype),
+            message: String::from_utf8_lossy(msg).into_owned(),
+        }
+        .into()
+    }
+
+    /// Apply NBD_REP_INFO payload to the export. Returns whether it was NBD_INFO_EXPORT.
+    fn parse_info(mut data: &[u8], export: &mut Export) -> Result<bool> {
+        let bad = || strerror("Malformed NBD_REP_INFO").unwrap_err();
+        let typ = data.read_u16::<BE>().map_err(|_| bad())?;
+        match typ {
+            NBD_INFO_EXPORT => {
+                if data.len() != 10 {
+                    return Err(bad());
+                }
+                export.size = data.read_u64::<BE>()?;
+                let flags = data.read_u16::<BE>()?;
+                fill_in_flags(export, flags);
+                return Ok(true);
+            }
+            NBD_INFO_NAME => {
+                export.name = Some(String::from_utf8_lossy(data).into_owned());
+            }
+            NBD_INFO_DESCRIPTION => {
+                export.description = Some(String::from_utf8_lossy(data).into_owned());
+            }
+            NBD_INFO_BLOCK_SIZE => {
+                if data.len() != 12 {
+                    return Err(bad());
+                }
+                export.block_size = Some(BlockSize {
+                    minimum: data.read_u32::<BE>()?,
+                    preferred: data.read_u32::<BE>()?,
+                    maximum: data.read_u32::<BE>()?,
+                });
+            }
+            _ => (), // unknown info types should be ignored
+        }
+        Ok(false)
+    }
+
+    /// Send an option without payload, returning whether server has acknowledged it
+    fn simple_option<IO: Write + Read>(mut c: IO, opt: u32) -> Result<bool> {
+        send_option(&mut c, opt, b"")?;
+        let (rtype, _) = read_option_reply(&mut c, opt)?;
+        if rtype != NBD_REP_ACK && rtype & NBD_REP_FLAG_ERROR == 0 {
+            strerror("Unexpected reply to an option")?;
+        }
+        Ok(rtype == NBD_REP_ACK)
+    }
+
+    /// Try NBD_OPT_GO. Returns `None` if server does not support it.
+    fn go<IO: Write + Read>(mut c: IO, name: &[u8]) -> Result<Option<Export>> {
+        let infos = [NBD_INFO_BLOCK_SIZE, NBD_INFO_NAME, NBD_INFO_DESCRIPTION];
+        let mut req = vec![];
+        req.write_u32::<BE>(name.len() as u32)?;
+        req.write_all(name)?;
+        req.write_u16::<BE>(infos.len() as u16)?;
+        for i in &infos {
+            req.write_u16::<BE>(*i)?;
+        }
+        send_option(&mut c, NBD_OPT_GO, &req)?;
+
+        let mut e = Export::default();
+        let mut got_export_info = false;
+        loop {
+            let (rtype, data) = read_option_reply(&mut c, NBD_OPT_GO)?;
+            match rtype {
+                NBD_REP_INFO => {
+                    if parse_info(&data, &mut e)? {
+                        got_export_info = true;
+                    }
+                }
+                NBD_REP_ACK => {
+                    if !got_export_info {
+                        strerror("Server have not sent NBD_INFO_EXPORT")?;
+                    }
+                    return Ok(Some(e));
+                }
+                NBD_REP_ERR_UNSUP => return Ok(None),
+                x if x & NBD_REP_FLAG_ERROR != 0 => return Err(option_error(x, &data)),
+                _ => strerror("Unexpected reply to NBD_OPT_GO")?,
+            }
+        }
+    }
+
+    /// Result of `negotiate`
+    #[derive(Debug)]
+    pub struct Negotiated {
+        /// Information about the selected export
+        pub export: Export,
+        /// Things to be passed to `NbdClient`
+        pub session: Session,
+    }
+
+    /// Protocol extensions agreed upon during negotiation
+    #[derive(Debug, Clone, Default)]
+    pub struct Session {
+        /// Server will use structured replies
+        pub structured_replies: bool,
+        /// Requests and replies use 64-bit extended headers. Implies `structured_replies`.
+        pub extended_headers: bool,
+        /// Metadata contexts selected for block status queries
+        pub meta_contexts: Vec<MetaContext>,
+        /// Server supports fixed newstyle negotiation, so options other than
+        /// NBD_OPT_EXPORT_NAME could be used. `false` for oldstyle servers.
+        pub fixed_newstyle: bool,
+        /// Server has omitted 124 bytes of zeroes after export flags (NBD_FLAG_NO_ZEROES)
+        pub no_zeroes: bool,
+    }
+
+    /// Client flags to reply to server's handshake flags with.
+    /// Only flags which server has advertised are set.
+    fn client_flags(hs_flags: u16) -> u32 {
+        u32::from(hs_flags) & (NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES)
+    }
+
+    /// Select metadata contexts with NBD_OPT_SET_META_CONTEXT.
+    /// Returns nothing if server refuses the option.
+    fn set_meta_context<IO: Write + Read>(
+        mut c: IO,
+        name: &[u8],
+        queries: &[&str],
+    ) -> Result<Vec<MetaContext>> {
+        let mut req = vec![];
+        req.write_u32::<BE>(name.len() as u32)?;
+        req.write_all(name)?;
+        req.write_u32::<BE>(queries.len() as u32)?;
+        for q in queries {
+            req.write_u32::<BE>(q.len() as u32)?;
+            req.write_all(q.as_bytes())?;
+        }
+        send_option(&mut c, NBD_OPT_SET_META_CONTEXT, &req)?;
+
+        let mut contexts = vec![];
+        loop {
+            let (rtype, data) = read_option_reply(&mut c, NBD_OPT_SET_META_CONTEXT)?;
+            match rtype {
+                NBD_REP_META_CONTEXT => {
+                    if data.len() < 4 {
+                        strerror("Malformed NBD_REP_META_CONTEXT")?;
+                    }
+                    let name = String::from_utf8_lossy(&data[4..]).into_owned();
+                    let id = (&data[..]).read_u32::<BE>()?;
+                    contexts.push(MetaContext { id, name });
+                }
+                NBD_REP_ACK => return Ok(contexts),
+                x if x & NBD_REP_FLAG_ERROR != 0 => return Ok(vec![]),
+                _ => strerror("Unexpected reply to NBD_OPT_SET_META_CONTEXT")?,
+            }
         }
     }
 
-    /// Negotiate with a server, use before creating the actual client
-    pub fn handshake<IO: Write + Read>(mut c: IO, name: &[u8]) -> Result<Export> {
+    /// Negotiate with a server, use before creating the actual client.
+    ///
+    /// Uses NBD_OPT_GO (which also retrieves block size constraints, canonical name and description
+    /// of the export) if server supports it, falling back to NBD_OPT_EXPORT_NAME otherwise.
+    /// Unknown export gets reported as `ErrorKind::NotFound`.
+    pub fn handshake<IO: Write + Read>(c: IO, name: &[u8]) -> Result<Export> {
+        Ok(negotiate_impl(c, name, None)?.export)
+    }
+
+    /// Like `handshake`, but also enables protocol extensions which `NbdClient` needs to be
+    /// told about (extended headers, `base:allocation` metadata context). Create the client with `NbdClient::from_negotiated`.
+    pub fn negotiate<IO: Write + Read>(c: IO, name: &[u8]) -> Result<Negotiated> {
+        negotiate_with(c, name, &Default::default())
+    }
+
+    /// Additional settings for `negotiate_with`
+    #[derive(Debug, Clone, Default)]
+    pub struct NegotiationOptions {
+        /// Metadata contexts to request in addition to `base:allocation`,
+        /// like `qemu:dirty-bitmap:backup0`. Server silently ignores unknown ones.
+        pub meta_contexts: Vec<String>,
+    }
+
+    /// Like `negotiate`, with additional settings
+    pub fn negotiate_with<IO: Write + Read>(
+        c: IO,
+        name: &[u8],
+        options: &NegotiationOptions,
+    ) -> Result<Negotiated> {
+        negotiate_impl(c, name, Some(options))
+    }
+
+    /// Read the initial magic and return the one following it
+    fn read_magic<IO: Read>(mut c: IO) -> Result<[u8; 8]> {
         let mut signature = [0; 8];
         c.read_exact(&mut signature)?;
 
@@ -364,66 +3014,212 @@ pub mod client {
         }
 
         c.read_exact(&mut signature)?;
+        Ok(signature)
+    }
+
+    /// Like `negotiate_with`, but first upgrades the connection to TLS with NBD_OPT_STARTTLS,
+    /// verifying the server according to `config`. Use the returned stream for `NbdClient`.
+    ///
+    /// Fails if server does not support TLS.
+    #[cfg(feature = "tls")]
+    pub fn negotiate_tls<IO: Write + Read>(
+        mut c: IO,
+        name: &[u8],
+        options: &NegotiationOptions,
+        config: ::std::sync::Arc<::rustls::ClientConfig>,
+        server_name: ::rustls::pki_types::ServerName<'static>,
+    ) -> Result<(Negotiated, ::tls::ClientStream<IO>)> {
+        if read_magic(&mut c)? != *b"IHAVEOPT" {
+            strerror("Server does not support newstyle negotiation, hence TLS")?;
+        }
+        let hs_flags = c.read_u16::<BE>()?;
+        if hs_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+            strerror("Server does not support fixed newstyle negotiation, hence TLS")?;
+        }
+        c.write_u32::<BE>(client_flags(hs_flags))?;
+
+        send_option(&mut c, NBD_OPT_STARTTLS, b"")?;
+        let (rtype, data) = read_option_reply(&mut c, NBD_OPT_STARTTLS)?;
+        match rtype {
+            NBD_REP_ACK => (),
+            x if x & NBD_REP_FLAG_ERROR != 0 => return Err(option_error(x, &data)),
+            _ => strerror("Unexpected reply to NBD_OPT_STARTTLS")?,
+        }
+
+        let conn = ::rustls::ClientConnection::new(config, server_name).map_err(::tls::error)?;
+        let mut s = ::tls::handshake(conn, c)?;
+        let n = negotiate_options(&mut s, name, Some(options), hs_flags)?;
+        Ok((n, ::tls::Stream::Tls(Box::new(s))))
+    }
+
+    /// `options` is `None` for legacy `handshake`
+    fn negotiate_impl<IO: Write + Read>(
+        mut c: IO,
+        name: &[u8],
+        options: Option<&NegotiationOptions>,
+    ) -> Result<Negotiated> {
+        let signature = read_magic(&mut c)?;
 
-        let (size, flags) = if signature == *b"IHAVEOPT" {
+        if signature == *b"IHAVEOPT" {
             // newstyle
-            let _hs_flags = c.read_u16::<BE>()?;
+            let hs_flags = c.read_u16::<BE>()?;
 
-            c.write_u32::<BE>(NBD_FLAG_C_FIXED_NEWSTYLE)?;
+            c.write_u32::<BE>(client_flags(hs_flags))?;
 
-            // optmagic
-            c.write_u64::<BE>(0x49484156454F5054)?;
-            c.write_u32::<BE>(NBD_OPT_EXPORT_NAME)?;
-            c.write_u32::<BE>(name.len() as u32)?;
-            c.write_all(name)?;
-            c.flush()?;
+            return negotiate_options(c, name, options, hs_flags);
+        }
+        if signature != *b"\x00\x00\x42\x02\x81\x86\x12\x53" {
+            strerror("Invalid magic2")?;
+        }
 
-            let size = c.read_u64::<BE>()?;
-            let flags = c.read_u16::<BE>()?;
-            let mut z = [0; 124];
-            c.read_exact(&mut z)?;
-            if z[..] != [0; 124][..] {
-                strerror("Expected 124 bytes of zeroes are not zeroes")?;
+        // oldstyle
+        if name != b"" {
+            strerror("Old style server does not support named exports")?;
+        };
+        let size = c.read_u64::<BE>()?;
+        // 32 bits of flags: handshake flags (none are defined), then transmission flags
+        let _hs_flags = c.read_u16::<BE>()?;
+        let flags = c.read_u16::<BE>()?;
+        let mut z = [0; 124];
+        c.read_exact(&mut z)?;
+        if z[..] != [0; 124][..] {
+            strerror("Expected 124 bytes of zeroes are not zeroes")?;
+        }
+
+        let mut e = Export {
+            size,
+            ..Default::default()
+        };
+
+        fill_in_flags(&mut e, flags);
+
+        Ok(Negotiated {
+            export: e,
+            session: Session::default(),
+        })
+    }
+
+    /// Newstyle negotiation after the client flags are sent
+    fn negotiate_options<IO: Write + Read>(
+        mut c: IO,
+        name: &[u8],
+        options: Option<&NegotiationOptions>,
+        hs_flags: u16,
+    ) -> Result<Negotiated> {
+        let legacy = options.is_none();
+        let mut session = Session {
+            fixed_newstyle: hs_flags & NBD_FLAG_FIXED_NEWSTYLE != 0,
+            no_zeroes: hs_flags & NBD_FLAG_NO_ZEROES != 0,
+            ..Default::default()
+        };
+        if session.fixed_newstyle {
+            if !legacy && simple_option(&mut c, NBD_OPT_EXTENDED_HEADERS)? {
+                session.extended_headers = true;
+                session.structured_replies = true;
             }
-            (size, flags)
-        } else if signature == *b"\x00\x00\x42\x02\x81\x86\x12\x53" {
-            // oldstyle.
-            // Note: not tested at all
-            if name != b"" {
-                strerror("Old style server does not support named exports")?;
-            };
-            let size = c.read_u64::<BE>()?;
-            let flags = c.read_u32::<BE>()?;
+            // NbdClient understands both simple and structured replies,
+            // so just ask server for structured replies and proceed regardless of the answer
+            if !session.structured_replies && simple_option(&mut c, NBD_OPT_STRUCTURED_REPLY)? {
+                session.structured_replies = true;
+            }
+            if let (Some(options), true) = (options, session.structured_replies) {
+                let mut queries = vec![BASE_ALLOCATION];
+                queries.extend(options.meta_contexts.iter().map(|x| &x[..]));
+                session.meta_contexts = set_meta_context(&mut c, name, &queries)?;
+            }
+
+            if let Some(export) = go(&mut c, name)? {
+                return Ok(Negotiated { export, session });
+            }
+        }
+
+        send_option(&mut c, NBD_OPT_EXPORT_NAME, name)?;
+
+        let size = c.read_u64::<BE>()?;
+        let flags = c.read_u16::<BE>()?;
+        if !session.no_zeroes {
             let mut z = [0; 124];
             c.read_exact(&mut z)?;
             if z[..] != [0; 124][..] {
                 strerror("Expected 124 bytes of zeroes are not zeroes")?;
             }
+        }
 
-            // Is it those flags or some other flags?
-            // Too lazy to actually look into NBD implementation.
-            let flags = flags as u16;
-
-            (size, flags)
-        } else {
-            strerror("Invalid magic2")?;
-            unreachable!()
+        let mut e = Export {
+            size,
+            ..Default::default()
         };
 
-        let mut e = Export::default();
-        e.size = size;
-
         fill_in_flags(&mut e, flags);
 
-        Ok(e)
+        Ok(Negotiated { export: e, session })
+    }
+
+    fn parse_listed_export(mut data: &[u8]) -> Result<ListedExport> {
+        let bad = || strerror("Malformed NBD_REP_SERVER").unwrap_err();
+        let namelen = data.read_u32::<BE>().map_err(|_| bad())? as usize;
+        if namelen > data.len() {
+            return Err(bad());
+        }
+        let (name, description) = data.split_at(namelen);
+        Ok(ListedExport {
+            name: String::from_utf8_lossy(name).into_owned(),
+            description: if description.is_empty() {
+                None
+            } else {
+                Some(String::from_utf8_lossy(description).into_owned())
+            },
+        })
+    }
+
+    /// Ask server for the list of its exports using NBD_OPT_LIST.
+    ///
+    /// This performs the whole negotiation and aborts it afterwards,
+    /// so `c` should be a fresh connection and cannot be used further.
+    pub fn list_exports<IO: Write + Read>(mut c: IO) -> Result<Vec<ListedExport>> {
+        let mut signature = [0; 8];
+        c.read_exact(&mut signature)?;
+        if signature != *b"NBDMAGIC" {
+            strerror("Invalid magic1")?;
+        }
+        c.read_exact(&mut signature)?;
+        if signature != *b"IHAVEOPT" {
+            strerror("Old style server does not support listing exports")?;
+        }
+        let hs_flags = c.read_u16::<BE>()?;
+        c.write_u32::<BE>(client_flags(hs_flags))?;
+
+        send_option(&mut c, NBD_OPT_LIST, b"")?;
+        let mut list = vec![];
+        loop {
+            let (rtype, data) = read_option_reply(&mut c, NBD_OPT_LIST)?;
+            match rtype {
+                NBD_REP_SERVER => list.push(parse_listed_export(&data)?),
+                NBD_REP_ACK => break,
+                x if x & NBD_REP_FLAG_ERROR != 0 => return Err(option_error(x, &data)),
+                _ => strerror("Unexpected reply to NBD_OPT_LIST")?,
+            }
+        }
+
+        // Server may just close the connection instead of replying, so ignore errors here
+        if send_option(&mut c, NBD_OPT_ABORT, b"").is_ok() {
+            let _ = read_option_reply(&mut c, NBD_OPT_ABORT);
+        }
+        Ok(list)
     }
 
     /// Represents NBD client. Use `Read`,`Write` and `Seek` trait methods,
-    /// but make sure those are block-aligned
+    /// but make sure those are block-aligned.
+    /// For many requests in flight use `submit` and `complete` instead.
     pub struct NbdClient<IO: Write + Read> {
         c: IO,
         seek_pos: u64,
-        size: u64,
+        export: Export,
+        session: Session,
+        fua: bool,
+        next_handle: u64,
+        in_flight: HashMap<u64, InFlight>,
+        finished: VecDeque<Completion>,
     }
 
     impl<IO: Write + Read> NbdClient<IO> {
@@ -433,7 +3229,86 @@ pub mod client {
             NbdClient {
                 c,
                 seek_pos: 0,
-                size: export.size,
+                export: export.clone(),
+                session: Session::default(),
+                fua: false,
+                next_handle: 0,
+                in_flight: HashMap::new(),
+                finished: VecDeque::new(),
+            }
+        }
+
+        /// Create new NbdClient from the result of `negotiate`
+        pub fn from_negotiated(c: IO, negotiated: &Negotiated) -> Self {
+            NbdClient {
+                c,
+                seek_pos: 0,
+                export: negotiated.export.clone(),
+                session: negotiated.session.clone(),
+                fua: false,
+                next_handle: 0,
+                in_flight: HashMap::new(),
+                finished: VecDeque::new(),
+            }
+        }
+
+        /// Make subsequent writes, trims and zeroings durable before they are acknowledged
+        /// by setting NBD_CMD_FLAG_FUA on them. They fail unless server advertises `send_fua`.
+        pub fn set_fua(&mut self, fua: bool) {
+            self.fua = fua;
+        }
+
+        /// The export as reported by server. Size is updated by `NbdExt::resize`.
+        pub fn export(&self) -> &Export {
+            &self.export
+        }
+
+        /// Fail locally on commands which server has not promised to handle
+        fn check_capabilities(&self, cmd: &Command) -> Result<()> {
+            let e = &self.export;
+            let modifies = matches!(
+                *cmd,
+                Command::Write { .. }
+                    | Command::Trim { .. }
+                    | Command::WriteZeroes { .. }
+                    | Command::Resize { .. }
+            );
+            if modifies && e.readonly {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Export is read-only",
+                ));
+            }
+            let unsupported = match *cmd {
+                Command::Flush if !e.send_flush => Some("NBD_CMD_FLUSH"),
+                Command::Trim { .. } if !e.send_trim => Some("NBD_CMD_TRIM"),
+                Command::WriteZeroes { .. } if !e.send_write_zeroes => Some("NBD_CMD_WRITE_ZEROES"),
+                Command::WriteZeroes { flags, .. } if flags.fast_zero && !e.send_fast_zero => {
+                    Some("NBD_CMD_FLAG_FAST_ZERO")
+                }
+                Command::Resize { .. } if !e.resizeable => Some("NBD_CMD_RESIZE"),
+                Command::Cache { .. } if !e.send_cache => Some("NBD_CMD_CACHE"),
+                Command::Write { .. } | Command::Trim { .. } | Command::WriteZeroes { .. }
+                    if self.fua && !e.send_fua =>
+                {
+                    Some("NBD_CMD_FLAG_FUA")
+                }
+                _ => None,
+            };
+            if let Some(x) = unsupported {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("Server does not support {}", x),
+                ));
+            }
+            Ok(())
+        }
+
+        fn fua_flag(&self) -> u16 {
+            if self.fua {
+                NBD_CMD_FLAG_FUA
+            } else {
+                0
             }
         }
     }
@@ -452,7 +3327,7 @@ pub mod client {
                     }
                 }
                 SeekFrom::End(x) => {
-                    if let Some(xx) = self.size.checked_add_i64(x) {
+                    if let Some(xx) = self.export.size.checked_add_i64(x) {
                         self.seek_pos = xx;
                     } else {
                         strerror("Invalid seek")?;
@@ -465,127 +3340,675 @@ pub mod client {
 
     fn check_err(error: u32) -> Result<()> {
         match error {
-            1 => Err(Error::new(ErrorKind::PermissionDenied, "from device")),
-            5 => Err(Error::new(ErrorKind::Other, "EIO")),
-            12 => Err(Error::new(ErrorKind::Other, "ENOMEM")),
-            22 => Err(Error::new(ErrorKind::Other, "EINVAL")),
-            28 => Err(Error::new(ErrorKind::Other, "ENOSPC")),
             0 => Ok(()),
-            _ => Err(Error::new(ErrorKind::Other, "other error from device")),
+            errno => Err(super::Error::Device {
+                errno,
+                message: None,
+            }
+            .into()),
         }
     }
 
-    fn getreply<IO: Write + Read>(mut c: IO) -> Result<()> {
-        let signature = c.read_u32::<BE>()?;
-        let error = c.read_u32::<BE>()?;
-        let handle = c.read_u64::<BE>()?;
-
-        if signature != 0x67446698 {
-            strerror("Invalid signature for incoming reply")?;
+    /// Receive a reply (simple or structured) to a request.
+    /// For NBD_CMD_READ, `read` should contain the offset of request and the buffer for the data.
+    fn structured_error(payload: &[u8]) -> Result<Error> {
+        let mut p = payload;
+        let error = p.read_u32::<BE>()?;
+        let msglen = p.read_u16::<BE>()? as usize;
+        if msglen > p.len() {
+            strerror("Malformed error chunk")?;
         }
-        if handle != 0 {
-            strerror("Unexpected handle")?;
+        if error == 0 {
+            strerror("Error chunk without error code")?;
+        }
+        let message = if msglen == 0 {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&p[..msglen]).into_owned())
         };
-        check_err(error)?;
-        Ok(())
+        Ok(super::Error::Device {
+            errno: error,
+            message,
+        }
+        .into())
     }
 
-    fn sendrequest<IO: Write + Read>(mut c: IO, cmd: u16, offset: u64, len: u32) -> Result<()> {
-        c.write_u32::<BE>(0x25609513)?;
-        c.write_u16::<BE>(0)?; // flags
+    /// Send request header. `len` must fit in 32 bits unless `extended` is set.
+    fn sendrequest<IO: Write + Read>(
+        mut c: IO,
+        extended: bool,
+        flags: u16,
+        cmd: u16,
+        handle: u64,
+        offset: u64,
+        len: u64,
+    ) -> Result<()> {
+        c.write_u32::<BE>(if extended { 0x21e41c71 } else { 0x25609513 })?;
+        c.write_u16::<BE>(flags)?;
         c.write_u16::<BE>(cmd)?;
-        c.write_u64::<BE>(0)?; // handle
+        c.write_u64::<BE>(handle)?;
         c.write_u64::<BE>(offset)?;
-        c.write_u32::<BE>(len)?;
+        if extended {
+            c.write_u64::<BE>(len)?;
+        } else {
+            c.write_u32::<BE>(len as u32)?;
+        }
         c.flush()?;
         Ok(())
     }
 
+    /// Command for `NbdClient::submit`
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    pub enum Command {
+        /// Read data
+        Read {
+            /// Start of the range
+            offset: u64,
+            /// Number of bytes to read
+            length: u64,
+        },
+        /// Write data
+        Write {
+            /// Where to write
+            offset: u64,
+            /// The data to write
+            data: Vec<u8>,
+        },
+        /// Commit completed writes to stable storage
+        Flush,
+        /// Discard the range
+        Trim {
+            /// Start of the range
+            offset: u64,
+            /// Length of the range
+            length: u64,
+        },
+        /// Fill the range with zeroes
+        WriteZeroes {
+            /// Start of the range
+            offset: u64,
+            /// Length of the range
+            length: u64,
+            /// NBD_CMD_FLAG_NO_HOLE and NBD_CMD_FLAG_FAST_ZERO
+            flags: WriteZeroesFlags,
+        },
+        /// Change size of the device
+        Resize {
+            /// New size in bytes
+            size: u64,
+        },
+        /// Hint server to prefetch the range
+        Cache {
+            /// Start of the range
+            offset: u64,
+            /// Length of the range
+            length: u64,
+        },
+        /// Query allocation status of the range (`base:allocation` metadata context)
+        BlockStatus {
+            /// Start of the range
+            offset: u64,
+            /// Length of the range
+            length: u64,
+        },
+    }
+
+    /// Finished command, returned by `NbdClient::complete`
+    #[derive(Debug)]
+    pub struct Completion {
+        /// Handle returned by `NbdClient::submit`
+        pub handle: u64,
+        /// Outcome of the command. Holds the data for `Command::Read`, empty otherwise.
+        pub result: Result<Vec<u8>>,
+        /// Extents reported for `Command::BlockStatus` in `base:allocation` context, empty otherwise
+        pub extents: Vec<Extent>,
+        /// Raw `(length, flags)` descriptors for `Command::BlockStatus` by metadata context id,
+        /// including `base:allocation`
+        pub statuses: Vec<(u32, Vec<(u64, u32)>)>,
+    }
+
+    /// Submitted request awaiting its reply
+    struct InFlight {
+        /// Offset and buffer for read requests
+        read: Option<(u64, Vec<u8>)>,
+        received: u64,
+        /// Extents received so far for block status requests
+        extents: Option<Vec<Extent>>,
+        statuses: Vec<(u32, Vec<(u64, u32)>)>,
+        error: Option<Error>,
+    }
+
+    impl InFlight {
+        /// Make completion of a request with all its replies received
+        fn finish(mut self, handle: u64) -> Completion {
+            let statuses = ::std::mem::take(&mut self.statuses);
+            let extents = self.extents.clone().unwrap_or_default();
+            match self.outcome(statuses.is_empty()) {
+                Ok(data) => Completion {
+                    handle,
+                    result: Ok(data),
+                    extents,
+                    statuses,
+                },
+                Err(e) => Completion {
+                    handle,
+                    result: Err(e),
+                    extents: vec![],
+                    statuses: vec![],
+                },
+            }
+        }
+
+        fn outcome(self, no_statuses: bool) -> Result<Vec<u8>> {
+            if let Some(e) = self.error {
+                return Err(e);
+            }
+            match self.read {
+                Some((_, buf)) => {
+                    if self.received != buf.len() as u64 {
+                        strerror("Server have not sent all the requested data")?;
+                    }
+                    Ok(buf)
+                }
+                None if self.extents.is_some() && no_statuses => {
+                    strerror("Server have not sent block status")?;
+                    unreachable!()
+                }
+                None => Ok(vec![]),
+            }
+        }
+    }
+
     impl<IO: Write + Read> NbdClient<IO> {
-        fn get_effective_len(&self, buflen: usize) -> Result<u32> {
-            if self.seek_pos == self.size {
+        /// Send a command without waiting for the reply and return its handle.
+        /// Any number of commands may be in flight; collect the results with `complete`.
+        ///
+        /// Commands which the export does not advertise support for (or modifications
+        /// of a read-only export) fail locally without being sent.
+        pub fn submit(&mut self, cmd: Command) -> Result<u64> {
+            self.check_capabilities(&cmd)?;
+            let ext = self.session.extended_headers;
+            let fua = self.fua_flag();
+            let (flags, typ, offset, length) = match cmd {
+                Command::Read { offset, length } => (0, NBD_CMD_READ, offset, length),
+                Command::Write { offset, ref data } => {
+                    (fua, NBD_CMD_WRITE, offset, data.len() as u64)
+                }
+                Command::Flush => (0, NBD_CMD_FLUSH, 0, 0),
+                Command::Trim { offset, length } => (fua, NBD_CMD_TRIM, offset, length),
+                Command::WriteZeroes {
+                    offset,
+                    length,
+                    flags,
+                } => {
+                    let mut f = fua;
+                    if flags.no_hole {
+                        f |= NBD_CMD_FLAG_NO_HOLE;
+                    }
+                    if flags.fast_zero {
+                        f |= NBD_CMD_FLAG_FAST_ZERO;
+                    }
+                    (f, NBD_CMD_WRITE_ZEROES, offset, length)
+                }
+                Command::Cache { offset, length } => (0, NBD_CMD_CACHE, offset, length),
+                Command::Resize { size } => (0, NBD_CMD_RESIZE, size, 0),
+                Command::BlockStatus { offset, length } => {
+                    (0, NBD_CMD_BLOCK_STATUS, offset, length)
+                }
+            };
+            if !ext && length > u32::MAX as u64 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Request is too long without extended headers",
+                ));
+            }
+
+            let handle = self.next_handle;
+            self.next_handle = self.next_handle.wrapping_add(1);
+            sendrequest(&mut self.c, ext, flags, typ, handle, offset, length)?;
+            let mut req = InFlight {
+                read: None,
+                received: 0,
+                extents: None,
+                statuses: vec![],
+                error: None,
+            };
+            match cmd {
+                Command::Write { ref data, .. } => {
+                    self.c.write_all(data)?;
+                    self.c.flush()?;
+                }
+                Command::Read { .. } => req.read = Some((offset, vec![0; length as usize])),
+                Command::BlockStatus { .. } => req.extents = Some(vec![]),
+                _ => (),
+            }
+            self.in_flight.insert(handle, req);
+            Ok(handle)
+        }
+
+        /// Wait for some submitted command to finish.
+        /// Replies are matched by handle, so commands may complete in any order.
+        pub fn complete(&mut self) -> Result<Completion> {
+            if let Some(x) = self.finished.pop_front() {
+                return Ok(x);
+            }
+            if self.in_flight.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "No commands in flight"));
+            }
+            loop {
+                if let Some(x) = self.receive()? {
+                    return Ok(x);
+                }
+            }
+        }
+
+        /// Number of submitted commands not yet returned by `complete`
+        pub fn pending(&self) -> usize {
+            self.in_flight.len() + self.finished.len()
+        }
+
+        /// Wait for the specific command, saving other completions for `complete`
+        fn wait(&mut self, handle: u64) -> Result<Vec<u8>> {
+            self.wait_completion(handle)?.result
+        }
+
+        fn wait_completion(&mut self, handle: u64) -> Result<Completion> {
+            if let Some(i) = self.finished.iter().position(|x| x.handle == handle) {
+                return Ok(self.finished.remove(i).unwrap());
+            }
+            loop {
+                if let Some(x) = self.receive()? {
+                    if x.handle == handle {
+                        return Ok(x);
+                    }
+                    self.finished.push_back(x);
+                }
+            }
+        }
+
+        /// Receive one simple reply or structured reply chunk.
+        /// Returns the completion if it finishes some command.
+        fn receive(&mut self) -> Result<Option<Completion>> {
+            let signature = self.c.read_u32::<BE>()?;
+            let extended = match signature {
+                0x67446698 => return self.receive_simple().map(Some),
+                0x668e33ef => false,
+                0x6e8a278c => true,
+                _ => {
+                    strerror("Invalid signature for incoming reply")?;
+                    unreachable!()
+                }
+            };
+
+            let flags = self.c.read_u16::<BE>()?;
+            let typ = self.c.read_u16::<BE>()?;
+            let handle = self.c.read_u64::<BE>()?;
+            let length = if extended {
+                let _offset = self.c.read_u64::<BE>()?;
+                self.c.read_u64::<BE>()?
+            } else {
+                self.c.read_u32::<BE>()? as u64
+            };
+
+            let base_allocation = self
+                .session
+                .meta_contexts
+                .iter()
+                .find(|x| x.name == BASE_ALLOCATION)
+                .map(|x| x.id);
+            let c = &mut self.c;
+            let req = match self.in_flight.get_mut(&handle) {
+                Some(x) => x,
+                None => {
+                    strerror("Unexpected handle")?;
+                    unreachable!()
+                }
+            };
+
+            match typ {
+                NBD_REPLY_TYPE_NONE => {
+                    if length != 0 {
+                        strerror("Non-empty NBD_REPLY_TYPE_NONE chunk")?;
+                    }
+                }
+                NBD_REPLY_TYPE_OFFSET_DATA | NBD_REPLY_TYPE_OFFSET_HOLE => {
+                    let (offset, buf) = match req.read {
+                        Some((offset, ref mut buf)) => (offset, buf),
+                        None => {
+                            strerror("Data chunk in reply to non-read request")?;
+                            unreachable!()
+                        }
+                    };
+                    if length < 8 {
+                        strerror("Malformed data chunk")?;
+                    }
+                    let chunk_offset = c.read_u64::<BE>()?;
+                    let chunk_len = if typ == NBD_REPLY_TYPE_OFFSET_DATA {
+                        length - 8
+                    } else {
+                        if length != 12 {
+                            strerror("Malformed hole chunk")?;
+                        }
+                        c.read_u32::<BE>()? as u64
+                    };
+                    let start = chunk_offset.wrapping_sub(offset);
+                    let buflen = buf.len() as u64;
+                    if chunk_offset < offset || start > buflen || chunk_len > buflen - start {
+                        strerror("Chunk is outside of the requested range")?;
+                    }
+                    let part = &mut buf[(start as usize)..(start as usize + chunk_len as usize)];
+                    if typ == NBD_REPLY_TYPE_OFFSET_DATA {
+                        c.read_exact(part)?;
+                    } else {
+                        for x in part.iter_mut() {
+                            *x = 0;
+                        }
+                    }
+                    req.received += chunk_len;
+                }
+                NBD_REPLY_TYPE_BLOCK_STATUS | NBD_REPLY_TYPE_BLOCK_STATUS_EXT => {
+                    let extents = match req.extents {
+                        Some(ref mut x) => x,
+                        None => {
+                            strerror("Block status chunk in reply to other request")?;
+                            unreachable!()
+                        }
+                    };
+                    if !(4..=1 << 24).contains(&length) {
+                        strerror("Malformed block status chunk")?;
+                    }
+                    let mut payload = vec![0; length as usize];
+                    c.read_exact(&mut payload)?;
+                    let mut p = &payload[..];
+                    let id = p.read_u32::<BE>()?;
+                    if typ == NBD_REPLY_TYPE_BLOCK_STATUS_EXT {
+                        let n = p.read_u32::<BE>()? as usize;
+                        if p.len() != n * 16 {
+                            strerror("Malformed block status chunk")?;
+                        }
+                    } else if p.len() % 8 != 0 {
+                        strerror("Malformed block status chunk")?;
+                    }
+                    let mut descriptors = vec![];
+                    while !p.is_empty() {
+                        let (length, flags) = if typ == NBD_REPLY_TYPE_BLOCK_STATUS_EXT {
+                            (p.read_u64::<BE>()?, p.read_u64::<BE>()? as u32)
+                        } else {
+                            (p.read_u32::<BE>()? as u64, p.read_u32::<BE>()?)
+                        };
+                        if Some(id) == base_allocation {
+                            extents.push(Extent {
+                                length,
+                                hole: flags & NBD_STATE_HOLE != 0,
+                                zero: flags & NBD_STATE_ZERO != 0,
+                            });
+                        }
+                        descriptors.push((length, flags));
+                    }
+                    req.statuses.push((id, descriptors));
+                }
+                x if x & (1 << 15) != 0 => {
+                    // NBD_REPLY_TYPE_ERROR, NBD_REPLY_TYPE_ERROR_OFFSET or some unknown error type
+                    if !(6..=100000).contains(&length) {
+                        strerror("Malformed error chunk")?;
+                    }
+                    let mut payload = vec![0; length as usize];
+                    c.read_exact(&mut payload)?;
+                    let e = structured_error(&payload)?;
+                    if req.error.is_none() {
+                        req.error = Some(e);
+                    }
+                }
+                _ => strerror("Unknown structured reply chunk type")?,
+            }
+
+            if flags & NBD_REPLY_FLAG_DONE == 0 {
+                return Ok(None);
+            }
+            let req = self.in_flight.remove(&handle).unwrap();
+            Ok(Some(req.finish(handle)))
+        }
+
+        /// Receive the rest of simple reply (after the magic)
+        fn receive_simple(&mut self) -> Result<Completion> {
+            let error = self.c.read_u32::<BE>()?;
+            let handle = self.c.read_u64::<BE>()?;
+            let mut req = match self.in_flight.remove(&handle) {
+                Some(x) => x,
+                None => {
+                    strerror("Unexpected handle")?;
+                    unreachable!()
+                }
+            };
+            if let Err(e) = check_err(error) {
+                req.error = Some(e);
+            } else if let Some((_, ref mut buf)) = req.read {
+                self.c.read_exact(buf)?;
+                req.received = buf.len() as u64;
+            }
+            Ok(req.finish(handle))
+        }
+
+        fn get_effective_len(&self, len: u64) -> Result<u64> {
+            let size = self.export.size;
+            if self.seek_pos == size {
                 return Ok(0);
             }
-            if self.seek_pos > self.size {
+            if self.seek_pos > size {
                 strerror("Trying to read or write past the end of the device")?;
             }
 
-            let maxlen = (self.size - self.seek_pos).clamp_to_u32();
-            let len = buflen.clamp_to_u32().min(maxlen);
-            Ok(len)
+            Ok(len.min(size - self.seek_pos))
+        }
+
+        /// Limit length of a request starting at `offset` to the advertised maximum block size,
+        /// ending it at a multiple of the minimum block size so that the rest of the range is aligned
+        fn clamp_to_block_size(&self, offset: u64, len: u64) -> u64 {
+            match self.export.block_size {
+                Some(bs) => {
+                    let min = u64::from(bs.minimum.max(1));
+                    let max = (u64::from(bs.maximum) / min).max(1) * min;
+                    len.min(max - offset % min)
+                }
+                None => len,
+            }
+        }
+
+        /// Issue commands over the given range, splitting it into multiple
+        /// requests unless extended headers are in use.
+        fn range_request<F>(&mut self, mut offset: u64, mut len: u64, cmd: F) -> Result<()>
+        where
+            F: Fn(u64, u64) -> Command,
+        {
+            while len > 0 {
+                let chunk = if self.session.extended_headers {
+                    len
+                } else {
+                    // keep the pieces aligned
+                    len.min(1 << 31)
+                };
+                let chunk = self.clamp_to_block_size(offset, chunk);
+                let h = self.submit(cmd(offset, chunk))?;
+                self.wait(h)?;
+                offset += chunk;
+                len -= chunk;
+            }
+            Ok(())
         }
     }
 
     impl<IO: Write + Read> Read for NbdClient<IO> {
         fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-            let len = self.get_effective_len(buf.len())?;
+            let len = self.get_effective_len(buf.len() as u64)?.clamp_to_u32();
+            let len = self.clamp_to_block_size(self.seek_pos, len.into()) as usize;
             if len == 0 {
                 return Ok(0);
             }
 
-            sendrequest(&mut self.c, NBD_CMD_READ, self.seek_pos, len)?;
+            let h = self.submit(Command::Read {
+                offset: self.seek_pos,
+                length: len as u64,
+            })?;
+            let data = self.wait(h)?;
+            buf[..len].copy_from_slice(&data);
 
-            getreply(&mut self.c)?;
-
-            self.c.read_exact(&mut buf[0..(len as usize)])?;
             self.seek_pos += len as u64;
-            Ok(len as usize)
+            Ok(len)
         }
     }
 
     impl<IO: Write + Read> Write for NbdClient<IO> {
         fn write(&mut self, buf: &[u8]) -> Result<usize> {
-            let len = self.get_effective_len(buf.len())?;
+            let len = self.get_effective_len(buf.len() as u64)?.clamp_to_u32();
+            let len = self.clamp_to_block_size(self.seek_pos, len.into()) as usize;
             if len == 0 {
                 return Ok(0);
             }
 
-            sendrequest(&mut self.c, NBD_CMD_WRITE, self.seek_pos, len)?;
+            let h = self.submit(Command::Write {
+                offset: self.seek_pos,
+                data: buf[..len].to_vec(),
+            })?;
+            self.wait(h)?;
 
-            self.c.write_all(&buf[0..(len as usize)])?;
-            self.c.flush()?;
-
-            getreply(&mut self.c)?;
             self.seek_pos += len as u64;
-            Ok(len as usize)
+            Ok(len)
         }
         fn flush(&mut self) -> Result<()> {
-            sendrequest(&mut self.c, NBD_CMD_FLUSH, 0, 0)?;
-            getreply(&mut self.c)?;
+            if !self.export.send_flush {
+                // Server has no volatile cache to flush
+                return Ok(());
+            }
+            let h = self.submit(Command::Flush)?;
+            self.wait(h)?;
             Ok(())
         }
     }
 
+    /// Flags for `NbdExt::write_zeroes`
+    #[derive(Debug, Clone, Copy, Default, Hash, Eq, PartialEq)]
+    pub struct WriteZeroesFlags {
+        /// Data must be actually written, not just trimmed (NBD_CMD_FLAG_NO_HOLE)
+        pub no_hole: bool,
+        /// Fail instead of falling back to slow zeroing (NBD_CMD_FLAG_FAST_ZERO).
+        /// Requires `send_fast_zero`.
+        pub fast_zero: bool,
+    }
+
     /// Additional operations (apart from reading and writing) supported by NBD extensions
-    /// Not tested yet.
     pub trait NbdExt {
-        /// Discard this data, starting from current seek offset up to specified length
+        /// Discard this data, starting from current seek offset up to specified length.
+        /// Requires `send_trim`.
         fn trim(&mut self, length: usize) -> Result<()>;
 
-        /// Change size of the device
+        /// Fill with zeroes, starting from current seek offset up to specified length.
+        /// Requires `send_write_zeroes`.
+        fn write_zeroes(&mut self, length: usize, flags: WriteZeroesFlags) -> Result<()>;
+
+        /// Change size of the device. Requires `resizeable`.
         fn resize(&mut self, newsize: u64) -> Result<()>;
+
+        /// Hint server that the range is going to be read soon.
+        /// Unlike other methods, does not depend on the seek offset.
+        /// Does nothing if server does not advertise `send_cache`.
+        fn cache(&mut self, offset: u64, length: u64) -> Result<()>;
+
+        /// Describe allocation of data starting from current seek offset.
+        /// Extents may cover less than `length`. Requires `base:allocation`
+        /// metadata context to be negotiated.
+        fn block_status(&mut self, length: u64) -> Result<Vec<Extent>>;
+
+        /// Like `block_status`, but for arbitrary negotiated metadata context.
+        /// Returns raw `(length, flags)` descriptors.
+        fn context_status(&mut self, context: &str, length: u64) -> Result<Vec<(u64, u32)>>;
     }
 
     impl<IO: Write + Read> NbdExt for NbdClient<IO> {
         fn trim(&mut self, length: usize) -> Result<()> {
-            let len = self.get_effective_len(length)?;
-            if len == 0 {
-                return Ok(());
-            }
-
-            sendrequest(&mut self.c, NBD_CMD_TRIM, self.seek_pos, len)?;
+            let len = self.get_effective_len(length as u64)?;
+            let offset = self.seek_pos;
+            self.range_request(offset, len, |offset, length| Command::Trim {
+                offset,
+                length,
+            })
+        }
 
-            getreply(&mut self.c)?;
+        fn write_zeroes(&mut self, length: usize, flags: WriteZeroesFlags) -> Result<()> {
+            let len = self.get_effective_len(length as u64)?;
+            let offset = self.seek_pos;
+            self.range_request(offset, len, |offset, length| Command::WriteZeroes {
+                offset,
+                length,
+                flags,
+            })
+        }
 
+        fn resize(&mut self, newsize: u64) -> Result<()> {
+            let h = self.submit(Command::Resize { size: newsize })?;
+            self.wait(h)?;
+            self.export.size = newsize;
             Ok(())
         }
 
-        fn resize(&mut self, newsize: u64) -> Result<()> {
-            sendrequest(&mut self.c, NBD_CMD_RESIZE, newsize, 0)?;
+        fn cache(&mut self, offset: u64, length: u64) -> Result<()> {
+            if !self.export.send_cache {
+                // Just a hint, nothing is lost by not sending it
+                return Ok(());
+            }
+            self.range_request(offset, length, |offset, length| Command::Cache {
+                offset,
+                length,
+            })
+        }
 
-            getreply(&mut self.c)?;
-            self.size = newsize;
-            Ok(())
+        fn block_status(&mut self, length: u64) -> Result<Vec<Extent>> {
+            let extents = self.context_status(BASE_ALLOCATION, length)?;
+            Ok(extents
+                .into_iter()
+                .map(|(length, flags)| Extent {
+                    length,
+                    hole: flags & NBD_STATE_HOLE != 0,
+                    zero: flags & NBD_STATE_ZERO != 0,
+                })
+                .collect())
+        }
+
+        fn context_status(&mut self, context: &str, length: u64) -> Result<Vec<(u64, u32)>> {
+            let id = match self
+                .session
+                .meta_contexts
+                .iter()
+                .find(|x| x.name == context)
+            {
+                Some(x) => x.id,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        format!("{} metadata context is not negotiated", context),
+                    ))
+                }
+            };
+            let mut length = self.get_effective_len(length)?;
+            if !self.session.extended_headers {
+                length = length.min(u32::MAX as u64);
+            }
+            if length == 0 {
+                return Ok(vec![]);
+            }
+            let h = self.submit(Command::BlockStatus {
+                offset: self.seek_pos,
+                length,
+            })?;
+            let c = self.wait_completion(h)?;
+            c.result?;
+            let statuses = c.statuses.into_iter().find(|x| x.0 == id);
+            match statuses {
+                Some((_, x)) => Ok(x),
+                None => {
+                    strerror("Server have not sent status for the metadata context")?;
+                    unreachable!()
+                }
+            }
         }
     }
 }
@@ -599,11 +4022,14 @@ mod consts {
     pub const NBD_OPT_INFO: u32 = 6;
     pub const NBD_OPT_GO: u32 = 7;
     pub const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+    pub const NBD_OPT_LIST_META_CONTEXT: u32 = 9;
+    pub const NBD_OPT_SET_META_CONTEXT: u32 = 10;
     pub const NBD_OPT_EXTENDED_HEADERS: u32 = 11;
 
     pub const NBD_REP_ACK: u32 = 1;
     pub const NBD_REP_SERVER: u32 = 2;
     pub const NBD_REP_INFO: u32 = 3;
+    pub const NBD_REP_META_CONTEXT: u32 = 4;
     pub const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
     pub const NBD_REP_ERR_UNSUP: u32 = 1 | NBD_REP_FLAG_ERROR;
     pub const NBD_REP_ERR_POLICY: u32 = 2 | NBD_REP_FLAG_ERROR;
@@ -611,7 +4037,9 @@ mod consts {
     pub const NBD_REP_ERR_PLATFORM: u32 = 4 | NBD_REP_FLAG_ERROR;
     pub const NBD_REP_ERR_TLS_REQD: u32 = 5 | NBD_REP_FLAG_ERROR;
     pub const NBD_REP_ERR_UNKNOWN: u32 = 6 | NBD_REP_FLAG_ERROR;
+    pub const NBD_REP_ERR_SHUTDOWN: u32 = 7 | NBD_REP_FLAG_ERROR;
     pub const NBD_REP_ERR_BLOCK_SIZE_REQD: u32 = 8 | NBD_REP_FLAG_ERROR;
+    pub const NBD_REP_ERR_TOO_BIG: u32 = 9 | NBD_REP_FLAG_ERROR;
 
     pub const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
     pub const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
@@ -633,14 +4061,40 @@ mod consts {
     pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
     pub const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
     pub const NBD_FLAG_SEND_RESIZE: u16 = 1 << 9;
+    pub const NBD_FLAG_SEND_CACHE: u16 = 1 << 10;
+    pub const NBD_FLAG_SEND_FAST_ZERO: u16 = 1 << 11;
+
+    pub const NBD_CMD_FLAG_FUA: u16 = 1 << 0;
+    pub const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+    pub const NBD_CMD_FLAG_REQ_ONE: u16 = 1 << 3;
+    pub const NBD_CMD_FLAG_FAST_ZERO: u16 = 1 << 4;
 
     pub const NBD_CMD_READ: u16 = 0;
     pub const NBD_CMD_WRITE: u16 = 1;
     pub const NBD_CMD_DISC: u16 = 2;
     pub const NBD_CMD_FLUSH: u16 = 3;
     pub const NBD_CMD_TRIM: u16 = 4;
+    pub const NBD_CMD_CACHE: u16 = 5;
     pub const NBD_CMD_WRITE_ZEROES: u16 = 6;
+    pub const NBD_CMD_BLOCK_STATUS: u16 = 7;
     pub const NBD_CMD_RESIZE: u16 = 8;
+
+    pub const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+
+    pub const NBD_REPLY_TYPE_NONE: u16 = 0;
+    pub const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+    pub const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+    pub const NBD_REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+    pub const NBD_REPLY_TYPE_BLOCK_STATUS_EXT: u16 = 6;
+    pub const NBD_REPLY_TYPE_ERROR: u16 = (1 << 15) + 1;
+    pub const NBD_REPLY_TYPE_ERROR_OFFSET: u16 = (1 << 15) + 2;
+
+    pub const NBD_STATE_HOLE: u32 = 1 << 0;
+    pub const NBD_STATE_ZERO: u32 = 1 << 1;
+    /// Used by `qemu:dirty-bitmap:*` contexts
+    pub const NBD_STATE_DIRTY: u32 = 1 << 0;
+
+    pub const BASE_ALLOCATION: &str = "base:allocation";
 }
 
 trait CheckedAddI64
@@ -670,8 +4124,8 @@ where
 }
 impl ClampToU32 for usize {
     fn clamp_to_u32(self) -> u32 {
-        if self > u32::max_value() as usize {
-            u32::max_value()
+        if self > u32::MAX as usize {
+            u32::MAX
         } else {
             self as u32
         }
@@ -679,8 +4133,8 @@ impl ClampToU32 for usize {
 }
 impl ClampToU32 for u64 {
     fn clamp_to_u32(self) -> u32 {
-        if self > u32::max_value() as u64 {
-            u32::max_value()
+        if self > u32::MAX as u64 {
+            u32::MAX
         } else {
             self as u32
         }
@@ -0,0 +1,167 @@
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{ErrorKind, Result, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use common::{socketpair, spawn_server, Client, Pipe};
+use nbd::client::{NbdClient, NbdExt};
+use nbd::server::{BlockDevice, MetaContextProvider};
+use nbd::Extent;
+
+const SIZE: u64 = 1 << 20;
+const DATA: u64 = 65536;
+
+/// Device with data in the first 64 KiB, the rest being a hole
+struct Sparse;
+
+impl BlockDevice for Sparse {
+    fn read_at(&mut self, buf: &mut [u8], _offset: u64) -> Result<()> {
+        for x in buf.iter_mut() {
+            *x = 0;
+        }
+        Ok(())
+    }
+    fn write_at(&mut self, _buf: &[u8], _offset: u64) -> Result<()> {
+        Ok(())
+    }
+    fn block_status(&mut self, offset: u64, length: u64) -> Result<Vec<Extent>> {
+        let hole = Extent {
+            length: SIZE - offset.max(DATA),
+            hole: true,
+            zero: true,
+        };
+        if offset >= DATA {
+            return Ok(vec![hole]);
+        }
+        let data = Extent {
+            length: (DATA - offset).min(length),
+            hole: false,
+            zero: false,
+        };
+        Ok(vec![data, hole])
+    }
+}
+
+fn export() -> nbd::Export {
+    nbd::Export {
+        size: SIZE,
+        ..Default::default()
+    }
+}
+
+fn connect(legacy: bool) -> NbdClient<Pipe> {
+    let how = if legacy {
+        Client::Handshake
+    } else {
+        Client::negotiate()
+    };
+    common::connect(Sparse, export(), Default::default(), how)
+}
+
+#[test]
+fn extents() {
+    let mut client = connect(false);
+
+    let e = client.block_status(SIZE).unwrap();
+    assert_eq!(e.len(), 2);
+    assert_eq!((e[0].length, e[0].hole, e[0].zero), (DATA, false, false));
+    assert_eq!(
+        (e[1].length, e[1].hole, e[1].zero),
+        (SIZE - DATA, true, true)
+    );
+
+    // Extents are clamped to the requested range
+    client.seek(SeekFrom::Start(4096)).unwrap();
+    let e = client.block_status(DATA).unwrap();
+    assert_eq!(e.len(), 2);
+    assert_eq!((e[0].length, e[0].hole), (DATA - 4096, false));
+    assert_eq!((e[1].length, e[1].hole), (4096, true));
+}
+
+#[test]
+fn not_negotiated() {
+    let mut client = connect(true);
+    let e = client.block_status(SIZE).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Unsupported);
+}
+
+/// Custom context marking every other 4 KiB block
+struct Striped;
+
+impl MetaContextProvider for Striped {
+    fn name(&self) -> &str {
+        "test:striped"
+    }
+    fn block_status(&self, offset: u64, length: u64) -> Result<Vec<(u64, u32)>> {
+        let mut v = vec![];
+        let mut pos = offset;
+        while pos < offset + length {
+            let end = (pos / 4096 + 1) * 4096;
+            v.push((end - pos, (pos / 4096 % 2) as u32));
+            pos = end;
+        }
+        Ok(v)
+    }
+}
+
+#[test]
+fn custom_context() {
+    let (mut s1, s2) = socketpair();
+    let mut options = nbd::server::NegotiationOptions::default();
+    options.meta_contexts.register(Arc::new(Striped));
+    spawn_server(s2, Sparse, export(), options);
+
+    let options = nbd::client::NegotiationOptions {
+        meta_contexts: vec!["test:striped".to_string(), "test:unknown".to_string()],
+    };
+    let n = nbd::client::negotiate_with(&mut s1, b"", &options).unwrap();
+    let names: Vec<_> = n
+        .session
+        .meta_contexts
+        .iter()
+        .map(|x| &x.name[..])
+        .collect();
+    assert_eq!(names, ["base:allocation", "test:striped"]);
+    let mut client = NbdClient::from_negotiated(s1, &n);
+
+    client.seek(SeekFrom::Start(6144)).unwrap();
+    let s = client.context_status("test:striped", 8192).unwrap();
+    assert_eq!(s, [(2048, 1), (4096, 0), (2048, 1)]);
+    // base:allocation is still reported alongside
+    let e = client.block_status(8192).unwrap();
+    assert_eq!((e[0].length, e[0].hole), (8192, false));
+
+    let e = client.context_status("test:unknown", 8192).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Unsupported);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn file_holes() {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = std::env::temp_dir().join(format!("nbd-status-test-{}", std::process::id()));
+    let mut f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    f.set_len(4 << 20).unwrap();
+    f.write_all(&[1; 65536]).unwrap();
+    f.sync_all().unwrap();
+
+    let e = BlockDevice::block_status(&mut f, 0, 4 << 20).unwrap();
+    assert_eq!(e.iter().map(|x| x.length).sum::<u64>(), 4 << 20);
+    assert!(!e[0].hole);
+    if f.metadata().unwrap().blocks() * 512 < 4 << 20 {
+        // filesystem supports sparse files
+        assert!(e.last().unwrap().hole);
+    }
+}
@@ -0,0 +1,431 @@
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use common::{connect, Client, FlushCounter, Pipe};
+use nbd::client::{NbdClient, NbdExt, WriteZeroesFlags};
+use nbd::server::{BlockDevice, NegotiationOptions, ReadWriteSeek, ResizePolicy};
+
+/// Export which advertises all the commands tested here
+fn capable(size: u64) -> nbd::Export {
+    nbd::Export {
+        size,
+        send_trim: true,
+        send_write_zeroes: true,
+        send_fast_zero: true,
+        send_fua: true,
+        send_cache: true,
+        ..Default::default()
+    }
+}
+
+/// Device of arbitrary size which only records trim requests
+struct TrimRecorder {
+    trims: Arc<Mutex<Vec<(u64, u64)>>>,
+}
+
+impl BlockDevice for TrimRecorder {
+    fn read_at(&mut self, buf: &mut [u8], _offset: u64) -> Result<()> {
+        for x in buf.iter_mut() {
+            *x = 0;
+        }
+        Ok(())
+    }
+    fn write_at(&mut self, _buf: &[u8], _offset: u64) -> Result<()> {
+        Ok(())
+    }
+    fn trim(&mut self, offset: u64, length: u64) -> Result<()> {
+        self.trims.lock().unwrap().push((offset, length));
+        Ok(())
+    }
+}
+
+#[test]
+fn trim_zeroes_memory() {
+    let mut client = connect(
+        Cursor::new(vec![0; 65536]),
+        capable(65536),
+        Default::default(),
+        Client::Handshake,
+    );
+    client.write_all(&[0xAA; 65536]).unwrap();
+    client.seek(SeekFrom::Start(4096)).unwrap();
+    client.trim(8192).unwrap();
+
+    let mut buf = vec![0; 65536];
+    client.seek(SeekFrom::Start(0)).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert!(buf[..4096].iter().all(|x| *x == 0xAA));
+    assert!(buf[4096..12288].iter().all(|x| *x == 0));
+    assert!(buf[12288..].iter().all(|x| *x == 0xAA));
+}
+
+fn large_trim(extended: bool) -> Vec<(u64, u64)> {
+    const GIB: u64 = 1 << 30;
+    let trims = Arc::new(Mutex::new(vec![]));
+    let dev = TrimRecorder {
+        trims: trims.clone(),
+    };
+    let how = if extended {
+        Client::negotiate()
+    } else {
+        Client::Handshake
+    };
+    let mut client = connect(dev, capable(8 * GIB), Default::default(), how);
+    client.seek(SeekFrom::Start(GIB)).unwrap();
+    client.trim(6 * GIB as usize).unwrap();
+    drop(client);
+    let trims = trims.lock().unwrap();
+    trims.clone()
+}
+
+#[test]
+fn large_trim_is_split_without_extended_headers() {
+    let g = 1 << 30;
+    assert_eq!(
+        large_trim(false),
+        vec![(g, 2 * g), (3 * g, 2 * g), (5 * g, 2 * g)]
+    );
+}
+
+#[test]
+fn large_trim_with_extended_headers() {
+    let g = 1 << 30;
+    assert_eq!(large_trim(true), vec![(g, 6 * g)]);
+}
+
+#[test]
+fn trim_punches_holes_in_file() {
+    let path = std::env::temp_dir().join(format!("nbd-trim-test-{}", std::process::id()));
+    let mut f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    f.write_all(&[0x55; 1 << 20]).unwrap();
+    f.trim(256 * 1024, 512 * 1024).unwrap();
+
+    let mut buf = vec![0; 1 << 20];
+    f.seek(SeekFrom::Start(0)).unwrap();
+    f.read_exact(&mut buf).unwrap();
+    assert_eq!(f.metadata().unwrap().len(), 1 << 20);
+    if cfg!(target_os = "linux") {
+        assert!(buf[256 * 1024..768 * 1024].iter().all(|x| *x == 0));
+    }
+    assert!(buf[..256 * 1024].iter().all(|x| *x == 0x55));
+    assert!(buf[768 * 1024..].iter().all(|x| *x == 0x55));
+}
+
+fn write_zeroes<D>(dev: D, flags: WriteZeroesFlags) -> Result<Vec<u8>>
+where
+    D: BlockDevice + Send + 'static,
+{
+    let mut client = connect(dev, capable(65536), Default::default(), Client::Handshake);
+    client.write_all(&[0xAA; 65536]).unwrap();
+    client.seek(SeekFrom::Start(4096)).unwrap();
+    client.write_zeroes(100_000, flags)?;
+
+    let mut buf = vec![0; 65536];
+    client.seek(SeekFrom::Start(0)).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    Ok(buf)
+}
+
+#[test]
+fn write_zeroes_efficient() {
+    let flags = WriteZeroesFlags {
+        fast_zero: true,
+        ..Default::default()
+    };
+    let buf = write_zeroes(Cursor::new(vec![0; 65536]), flags).unwrap();
+    assert!(buf[..4096].iter().all(|x| *x == 0xAA));
+    assert!(buf[4096..].iter().all(|x| *x == 0));
+}
+
+#[test]
+fn write_zeroes_fallback() {
+    let flags = WriteZeroesFlags {
+        no_hole: true,
+        ..Default::default()
+    };
+    let buf = write_zeroes(ReadWriteSeek(Cursor::new(vec![0; 65536])), flags).unwrap();
+    assert!(buf[..4096].iter().all(|x| *x == 0xAA));
+    assert!(buf[4096..].iter().all(|x| *x == 0));
+}
+
+#[test]
+fn write_zeroes_fast_unsupported() {
+    let flags = WriteZeroesFlags {
+        fast_zero: true,
+        ..Default::default()
+    };
+    assert!(write_zeroes(ReadWriteSeek(Cursor::new(vec![0; 65536])), flags).is_err());
+}
+
+#[test]
+fn fast_zero_not_advertised() {
+    let export = nbd::Export {
+        send_fast_zero: false,
+        ..capable(65536)
+    };
+    let dev = Cursor::new(vec![0; 65536]);
+    let mut client = connect(dev, export, Default::default(), Client::Handshake);
+    assert!(client.export().send_write_zeroes);
+    assert!(!client.export().send_fast_zero);
+
+    let flags = WriteZeroesFlags {
+        fast_zero: true,
+        ..Default::default()
+    };
+    let e = client.write_zeroes(4096, flags).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Unsupported);
+    client.write_zeroes(4096, Default::default()).unwrap();
+}
+
+#[test]
+fn fua_flushes_each_write() {
+    let (dev, flushes) = FlushCounter::new(65536);
+    let mut client = connect(dev, capable(65536), Default::default(), Client::Handshake);
+
+    client.write_all(&[1; 4096]).unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 0);
+
+    client.set_fua(true);
+    client.write_all(&[2; 4096]).unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    client.write_zeroes(4096, Default::default()).unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 2);
+
+    client.set_fua(false);
+    client.write_all(&[3; 4096]).unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 2);
+}
+
+/// In-memory device which cannot be flushed
+struct FailingFlush(Cursor<Vec<u8>>);
+
+impl BlockDevice for FailingFlush {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.0.read_at(buf, offset)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        self.0.write_at(buf, offset)
+    }
+    fn flush(&mut self) -> Result<()> {
+        Err(Error::new(ErrorKind::StorageFull, "disk is gone"))
+    }
+}
+
+#[test]
+fn flush_errors_are_reported() {
+    let dev = FailingFlush(Cursor::new(vec![0; 65536]));
+    let mut client = connect(dev, capable(65536), Default::default(), Client::Handshake);
+
+    client.write_all(&[1; 4096]).unwrap();
+    assert_eq!(client.flush().unwrap_err().kind(), ErrorKind::StorageFull);
+    client.set_fua(true);
+    assert_eq!(
+        client.write(&[2; 4096]).unwrap_err().kind(),
+        ErrorKind::StorageFull
+    );
+
+    // Connection survives both
+    let mut buf = [0; 4096];
+    client.seek(SeekFrom::Start(0)).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &[1; 4096][..]);
+}
+
+#[test]
+fn fua_on_file() {
+    let path = std::env::temp_dir().join(format!("nbd-fua-test-{}", std::process::id()));
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    f.set_len(65536).unwrap();
+    let mut client = connect(f, capable(65536), Default::default(), Client::Handshake);
+
+    client.set_fua(true);
+    client.write_all(&[7; 4096]).unwrap();
+    client.flush().unwrap();
+    let data = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&data[..4096], &[7; 4096][..]);
+}
+
+fn connect_resizeable(resizeable: bool) -> NbdClient<Pipe> {
+    let options = NegotiationOptions {
+        resize_policy: ResizePolicy::new(|_old, new| {
+            if new > 1 << 20 {
+                return Err(Error::new(ErrorKind::PermissionDenied, "too large"));
+            }
+            Ok(())
+        }),
+        ..Default::default()
+    };
+    let export = nbd::Export {
+        size: 65536,
+        resizeable,
+        ..Default::default()
+    };
+    let client = connect(
+        Cursor::new(vec![0; 65536]),
+        export,
+        options,
+        Client::Handshake,
+    );
+    assert_eq!(client.export().resizeable, resizeable);
+    client
+}
+
+#[test]
+fn resize() {
+    let mut client = connect_resizeable(true);
+    client.resize(131072).unwrap();
+    client.seek(SeekFrom::Start(100_000)).unwrap();
+    client.write_all(&[7; 4096]).unwrap();
+
+    let mut buf = vec![0; 4096];
+    client.seek(SeekFrom::Start(100_000)).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|x| *x == 7));
+
+    // Refused by policy, size stays the same
+    assert!(client.resize(2 << 20).is_err());
+    assert_eq!(client.seek(SeekFrom::End(0)).unwrap(), 131072);
+    client.resize(4096).unwrap();
+}
+
+#[test]
+fn resize_beyond_memory() {
+    let export = nbd::Export {
+        size: 65536,
+        resizeable: true,
+        ..Default::default()
+    };
+    let dev = Cursor::new(vec![0; 65536]);
+    let mut client = connect(dev, export, Default::default(), Client::Handshake);
+    let e = client.resize(i64::MAX as u64).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::StorageFull);
+    assert_eq!(client.seek(SeekFrom::End(0)).unwrap(), 65536);
+    // Connection is still usable
+    client.seek(SeekFrom::Start(0)).unwrap();
+    client.write_all(&[1; 4096]).unwrap();
+}
+
+#[test]
+fn resize_not_resizeable() {
+    let mut client = connect_resizeable(false);
+    assert!(client.resize(131072).is_err());
+    // Connection is still usable
+    client.write_all(&[1; 4096]).unwrap();
+}
+
+/// In-memory device which records prefetch hints
+struct CacheRecorder {
+    inner: Cursor<Vec<u8>>,
+    caches: Arc<Mutex<Vec<(u64, u64)>>>,
+}
+
+impl BlockDevice for CacheRecorder {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.inner.read_at(buf, offset)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        self.inner.write_at(buf, offset)
+    }
+    fn cache(&mut self, offset: u64, length: u64) -> Result<()> {
+        self.caches.lock().unwrap().push((offset, length));
+        Ok(())
+    }
+}
+
+#[test]
+fn cache() {
+    let caches = Arc::new(Mutex::new(vec![]));
+    let dev = CacheRecorder {
+        inner: Cursor::new(vec![0; 65536]),
+        caches: caches.clone(),
+    };
+    let mut client = connect(dev, capable(65536), Default::default(), Client::Handshake);
+    client.cache(4096, 8192).unwrap();
+    client.write_all(&[1; 4096]).unwrap();
+    assert_eq!(*caches.lock().unwrap(), [(4096, 8192)]);
+}
+
+#[test]
+fn cache_fallback_reads() {
+    let mut client = connect(
+        ReadWriteSeek(Cursor::new(vec![0; 65536])),
+        capable(65536),
+        Default::default(),
+        Client::Handshake,
+    );
+    client.cache(0, 65536).unwrap();
+    // Reading past the end of the backend fails
+    assert!(client.cache(60000, 10000).is_err());
+}
+
+#[test]
+fn capabilities_are_enforced() {
+    let mut client = connect_resizeable(false);
+    assert!(client.export().send_flush);
+    assert!(!client.export().send_trim);
+
+    let kind = |r: Result<()>| r.unwrap_err().kind();
+    assert_eq!(kind(client.trim(4096)), ErrorKind::Unsupported);
+    let flags = WriteZeroesFlags::default();
+    assert_eq!(
+        kind(client.write_zeroes(4096, flags)),
+        ErrorKind::Unsupported
+    );
+    assert_eq!(kind(client.resize(131072)), ErrorKind::Unsupported);
+    client.set_fua(true);
+    assert_eq!(kind(client.write_all(&[1; 4096])), ErrorKind::Unsupported);
+    client.set_fua(false);
+    // Hint is skipped
+    client.cache(0, 4096).unwrap();
+
+    // Nothing was sent, connection is still usable
+    client.write_all(&[1; 4096]).unwrap();
+    client.flush().unwrap();
+}
+
+#[test]
+fn readonly_export() {
+    let export = nbd::Export {
+        size: 65536,
+        readonly: true,
+        send_trim: true,
+        ..Default::default()
+    };
+    let dev = Cursor::new(vec![3; 65536]);
+    let mut client = connect(dev, export, Default::default(), Client::Handshake);
+
+    let e = client.write_all(&[1; 4096]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    assert_eq!(
+        client.trim(4096).unwrap_err().kind(),
+        ErrorKind::PermissionDenied
+    );
+    // No NBD_FLAG_SEND_FLUSH, nothing to flush
+    client.flush().unwrap();
+
+    let mut buf = vec![0; 4096];
+    client.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|x| *x == 3));
+}
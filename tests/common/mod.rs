@@ -0,0 +1,113 @@
+//! Connection setup shared by integration tests
+#![allow(dead_code)]
+
+use std::io::{Cursor, Read, Result, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use nbd::client::NbdClient;
+use nbd::server::{BlockDevice, NegotiationOptions};
+use readwrite::ReadWrite;
+
+/// In-memory stream, one side of `socketpair`
+pub type Pipe = ReadWrite<pipe::PipeReader, pipe::PipeWriter>;
+
+/// Two connected in-memory streams. Unbuffered: writes block until the peer reads.
+pub fn socketpair() -> (Pipe, Pipe) {
+    let (r1, w1) = pipe::pipe();
+    let (r2, w2) = pipe::pipe();
+    (ReadWrite::new(r1, w2), ReadWrite::new(r2, w1))
+}
+
+/// How the client negotiates
+pub enum Client {
+    /// `client::handshake`, without protocol extensions
+    Handshake,
+    /// `client::negotiate_with`
+    Negotiate(nbd::client::NegotiationOptions),
+}
+
+impl Client {
+    /// `client::negotiate` with default options
+    pub fn negotiate() -> Client {
+        Client::Negotiate(Default::default())
+    }
+}
+
+/// Negotiate `export` and serve `dev` over `s` in a background thread
+pub fn spawn_server<S, D>(
+    mut s: S,
+    dev: D,
+    export: nbd::Export,
+    options: NegotiationOptions,
+) -> JoinHandle<Result<()>>
+where
+    S: Read + Write + Send + 'static,
+    D: BlockDevice + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let n = nbd::server::negotiate(&mut s, &options, |_| Ok(export.clone()))?;
+        nbd::server::serve(&mut s, dev, &n.session)
+    })
+}
+
+/// Negotiate the default export over `s` and create a client
+pub fn client<S: Read + Write>(mut s: S, client: &Client) -> NbdClient<S> {
+    match *client {
+        Client::Handshake => {
+            let export = nbd::client::handshake(&mut s, b"").unwrap();
+            NbdClient::new(s, &export)
+        }
+        Client::Negotiate(ref options) => {
+            let n = nbd::client::negotiate_with(&mut s, b"", options).unwrap();
+            NbdClient::from_negotiated(s, &n)
+        }
+    }
+}
+
+/// Serve `dev` as `export` and connect a client to it
+pub fn connect<D>(
+    dev: D,
+    export: nbd::Export,
+    options: NegotiationOptions,
+    how: Client,
+) -> NbdClient<Pipe>
+where
+    D: BlockDevice + Send + 'static,
+{
+    let (s1, s2) = socketpair();
+    spawn_server(s2, dev, export, options);
+    client(s1, &how)
+}
+
+/// In-memory device which counts flushes
+pub struct FlushCounter {
+    inner: Cursor<Vec<u8>>,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl FlushCounter {
+    /// Zeroed device of given size and its flush count
+    pub fn new(size: usize) -> (FlushCounter, Arc<AtomicUsize>) {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let dev = FlushCounter {
+            inner: Cursor::new(vec![0; size]),
+            flushes: flushes.clone(),
+        };
+        (dev, flushes)
+    }
+}
+
+impl BlockDevice for FlushCounter {
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.inner.read_at(buf, offset)
+    }
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<()> {
+        self.inner.write_at(buf, offset)
+    }
+    fn flush(&mut self) -> Result<()> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
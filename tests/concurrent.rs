@@ -0,0 +1,172 @@
+#![cfg(unix)]
+extern crate nbd;
+
+use std::io::{Read, Result, Seek, SeekFrom, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use nbd::client::{Command, NbdClient};
+use nbd::server::SharedBlockDevice;
+
+/// In-memory device on which reading the first block takes long time
+struct SlowStart(Mutex<Vec<u8>>);
+
+impl SharedBlockDevice for SlowStart {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        if offset == 0 {
+            std::thread::sleep(Duration::from_millis(300));
+        }
+        let v = self.0.lock().unwrap();
+        buf.copy_from_slice(&v[offset as usize..offset as usize + buf.len()]);
+        Ok(())
+    }
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        let mut v = self.0.lock().unwrap();
+        v[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn connect<D>(dev: D, size: u64) -> NbdClient<UnixStream>
+where
+    D: SharedBlockDevice + Send + 'static,
+{
+    let (mut s1, mut s2) = UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let n = nbd::server::negotiate(&mut s2, &Default::default(), |_| {
+            Ok(nbd::Export::<()> {
+                size,
+                ..Default::default()
+            })
+        })
+        .unwrap();
+        nbd::server::serve_concurrent(&s2, &s2, &dev, &n.session, 4).unwrap();
+    });
+    let n = nbd::client::negotiate(&mut s1, b"").unwrap();
+    NbdClient::from_negotiated(s1, &n)
+}
+
+#[test]
+fn slow_read_does_not_stall_others() {
+    let mut client = connect(SlowStart(Mutex::new(vec![7; 8192])), 8192);
+    let slow = client
+        .submit(Command::Read {
+            offset: 0,
+            length: 4096,
+        })
+        .unwrap();
+    let fast = client
+        .submit(Command::Read {
+            offset: 4096,
+            length: 4096,
+        })
+        .unwrap();
+
+    let c = client.complete().unwrap();
+    assert_eq!(c.handle, fast);
+    assert_eq!(c.result.unwrap(), vec![7; 4096]);
+    let c = client.complete().unwrap();
+    assert_eq!(c.handle, slow);
+    assert_eq!(c.result.unwrap(), vec![7; 4096]);
+}
+
+#[test]
+fn oversized_read_is_refused() {
+    let size = 2 * nbd::server::MAX_CONCURRENT_REQUEST;
+    let mut client = connect(SlowStart(Mutex::new(vec![7; 8192])), size);
+    client
+        .submit(Command::Read {
+            offset: 0,
+            length: nbd::server::MAX_CONCURRENT_REQUEST + 1,
+        })
+        .unwrap();
+    let e = client.complete().unwrap().result.unwrap_err();
+    match nbd::Error::from(e) {
+        nbd::Error::Device { errno, .. } => assert_eq!(errno, nbd::errno::EOVERFLOW),
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    client.seek(SeekFrom::Start(4096)).unwrap();
+    let mut buf = [0; 4096];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &[7; 4096][..]);
+}
+
+#[test]
+fn file_roundtrip() {
+    let path = std::env::temp_dir().join(format!("nbd-concurrent-test-{}", std::process::id()));
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    f.set_len(1 << 20).unwrap();
+
+    let mut client = connect(f, 1 << 20);
+    for i in 0..16u8 {
+        client
+            .submit(Command::Write {
+                offset: i as u64 * 65536,
+                data: vec![i; 65536],
+            })
+            .unwrap();
+    }
+    while client.pending() > 0 {
+        client.complete().unwrap().result.unwrap();
+    }
+    client.flush().unwrap();
+
+    let mut buf = vec![0; 1 << 20];
+    client.seek(SeekFrom::Start(0)).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    for (i, x) in buf.chunks(65536).enumerate() {
+        assert!(x.iter().all(|x| *x == i as u8));
+    }
+}
+
+#[test]
+fn shutdown_finishes_in_flight_requests() {
+    let (mut s1, mut s2) = UnixStream::pair().unwrap();
+    let options = nbd::server::NegotiationOptions::default();
+    let handle = options.shutdown.clone();
+    let server = std::thread::spawn(move || {
+        let n = nbd::server::negotiate(&mut s2, &options, |_| {
+            Ok(nbd::Export::<()> {
+                size: 8192,
+                ..Default::default()
+            })
+        })
+        .unwrap();
+        let dev = SlowStart(Mutex::new(vec![7; 8192]));
+        nbd::server::serve_concurrent(&s2, &s2, &dev, &n.session, 4)
+    });
+    let n = nbd::client::negotiate(&mut s1, b"").unwrap();
+    let mut client = NbdClient::from_negotiated(s1, &n);
+
+    let read = |offset| Command::Read {
+        offset,
+        length: 4096,
+    };
+    let slow = client.submit(read(0)).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    handle.shutdown();
+    let rejected = client.submit(read(4096)).unwrap();
+
+    let c = client.complete().unwrap();
+    assert_eq!(c.handle, rejected);
+    let e = c.result.unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
+    let c = client.complete().unwrap();
+    assert_eq!(c.handle, slow);
+    assert_eq!(c.result.unwrap(), vec![7; 4096]);
+
+    drop(client);
+    server.join().unwrap().unwrap();
+}
@@ -0,0 +1,134 @@
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{Cursor, Result, Seek, SeekFrom, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use common::{Client, Pipe};
+use nbd::client::{NbdClient, NbdExt};
+use nbd::server::{DirtyBitmap, DirtyTracking, SharedBlockDevice};
+
+const SIZE: u64 = 1 << 20;
+const G: u64 = 65536;
+const CONTEXT: &str = "qemu:dirty-bitmap:backup0";
+
+fn connect(bitmap: Arc<DirtyBitmap>) -> NbdClient<Pipe> {
+    let mut options = nbd::server::NegotiationOptions::default();
+    options.meta_contexts.register(bitmap.clone());
+    let export = nbd::Export {
+        size: SIZE,
+        send_trim: true,
+        ..Default::default()
+    };
+    let dev = DirtyTracking {
+        inner: Cursor::new(vec![0; SIZE as usize]),
+        bitmap,
+    };
+    let how = Client::Negotiate(nbd::client::NegotiationOptions {
+        meta_contexts: vec![CONTEXT.to_string()],
+    });
+    common::connect(dev, export, options, how)
+}
+
+#[test]
+fn writes_are_tracked() {
+    let bitmap = Arc::new(DirtyBitmap::new("backup0", G));
+    let mut client = connect(bitmap.clone());
+
+    client.seek(SeekFrom::Start(G - 1)).unwrap();
+    client.write_all(&[1; 2]).unwrap();
+    client.seek(SeekFrom::Start(5 * G)).unwrap();
+    client.trim(100).unwrap();
+    assert_eq!(bitmap.dirty_ranges(), [(0, 2 * G), (5 * G, G)]);
+
+    client.seek(SeekFrom::Start(0)).unwrap();
+    let s = client.context_status(CONTEXT, SIZE).unwrap();
+    assert_eq!(s, [(2 * G, 1), (3 * G, 0), (G, 1), (SIZE - 6 * G, 0)]);
+
+    // Backup takes the changes so far, new writes go to the live bitmap
+    let snapshot = bitmap.snapshot_and_clear("backup1");
+    client.seek(SeekFrom::Start(3 * G)).unwrap();
+    client.write_all(&[1; 10]).unwrap();
+    assert_eq!(snapshot.dirty_ranges(), [(0, 2 * G), (5 * G, G)]);
+    assert_eq!(bitmap.dirty_ranges(), [(3 * G, G)]);
+}
+
+#[test]
+fn save_load_and_clear() {
+    let bitmap = DirtyBitmap::new("b", 4096);
+    bitmap.mark(4096, 3 * 4096);
+    bitmap.mark(100_000, 1);
+
+    let mut saved = vec![];
+    bitmap.save(&mut saved).unwrap();
+    let loaded = DirtyBitmap::load("b", &saved[..]).unwrap();
+    assert_eq!(loaded.granularity(), 4096);
+    assert_eq!(loaded.dirty_ranges(), bitmap.dirty_ranges());
+
+    // Only fully covered blocks are cleared
+    loaded.clear_range(6000, 4096 * 2);
+    assert_eq!(
+        loaded.dirty_ranges(),
+        [(4096, 4096), (3 * 4096, 4096), (98304, 4096)]
+    );
+    assert!(loaded.is_dirty(0, 5000));
+    assert!(!loaded.is_dirty(8192, 4096));
+    loaded.clear();
+    assert!(loaded.dirty_ranges().is_empty());
+
+    assert!(DirtyBitmap::load("b", &b"garbage"[..]).is_err());
+}
+
+/// Device whose writes report their start and wait to be let through
+struct Gate {
+    started: Mutex<Sender<()>>,
+    release: Mutex<Receiver<()>>,
+}
+
+impl SharedBlockDevice for Gate {
+    fn read_at(&self, buf: &mut [u8], _offset: u64) -> Result<()> {
+        for x in buf.iter_mut() {
+            *x = 0;
+        }
+        Ok(())
+    }
+    fn write_at(&self, _buf: &[u8], _offset: u64) -> Result<()> {
+        self.started.lock().unwrap().send(()).unwrap();
+        self.release.lock().unwrap().recv().unwrap();
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn snapshot_during_write() {
+    let (started_tx, started) = channel();
+    let (release, release_rx) = channel();
+    let bitmap = Arc::new(DirtyBitmap::new("backup0", G));
+    let dev = Arc::new(DirtyTracking {
+        inner: Gate {
+            started: Mutex::new(started_tx),
+            release: Mutex::new(release_rx),
+        },
+        bitmap: bitmap.clone(),
+    });
+
+    let writer = {
+        let dev = dev.clone();
+        std::thread::spawn(move || dev.write_at(&[1; 512], G))
+    };
+    started.recv().unwrap();
+    // Backup may read the old data, so the write must stay in the live bitmap
+    let snapshot = bitmap.snapshot_and_clear("backup1");
+    release.send(()).unwrap();
+    writer.join().unwrap().unwrap();
+
+    assert!(snapshot.dirty_ranges().is_empty());
+    assert_eq!(bitmap.dirty_ranges(), [(G, G)]);
+}
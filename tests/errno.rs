@@ -0,0 +1,87 @@
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{Error, ErrorKind, Result, Write};
+
+use common::{connect, Client};
+use nbd::errno;
+use nbd::server::BlockDevice;
+
+/// Device which fails every write with the given kind of error
+struct Failing(ErrorKind);
+
+impl BlockDevice for Failing {
+    fn read_at(&mut self, buf: &mut [u8], _offset: u64) -> Result<()> {
+        for x in buf.iter_mut() {
+            *x = 0;
+        }
+        Ok(())
+    }
+    fn write_at(&mut self, _buf: &[u8], _offset: u64) -> Result<()> {
+        Err(Error::new(self.0, "failing"))
+    }
+}
+
+fn write_error(kind: ErrorKind) -> Error {
+    let export = nbd::Export {
+        size: 65536,
+        ..Default::default()
+    };
+    let mut client = connect(Failing(kind), export, Default::default(), Client::Handshake);
+    client.write_all(&[1; 512]).unwrap_err()
+}
+
+#[test]
+fn kinds_survive_the_wire() {
+    for &kind in &[
+        ErrorKind::PermissionDenied,
+        ErrorKind::OutOfMemory,
+        ErrorKind::InvalidInput,
+        ErrorKind::StorageFull,
+        ErrorKind::Unsupported,
+        ErrorKind::Other,
+    ] {
+        assert_eq!(write_error(kind).kind(), kind);
+    }
+    let e = nbd::Error::from(write_error(ErrorKind::NotFound));
+    match e {
+        nbd::Error::Device { errno, .. } => assert_eq!(errno, errno::EIO),
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn mapping() {
+    let kind = |k| errno::from_io_error(&Error::from(k));
+    assert_eq!(kind(ErrorKind::ReadOnlyFilesystem), errno::EPERM);
+    assert_eq!(kind(ErrorKind::QuotaExceeded), errno::ENOSPC);
+    assert_eq!(kind(ErrorKind::UnexpectedEof), errno::EIO);
+
+    let proxied = nbd::Error::Device {
+        errno: errno::EOVERFLOW,
+        message: None,
+    };
+    let proxied = Error::from(proxied);
+    assert_eq!(proxied.kind(), ErrorKind::InvalidInput);
+    assert_eq!(errno::from_io_error(&proxied), errno::EOVERFLOW);
+
+    assert_eq!(
+        errno::to_error_kind(errno::ESHUTDOWN),
+        ErrorKind::ConnectionAborted
+    );
+    assert_eq!(errno::to_error_kind(1000), ErrorKind::Other);
+    assert_eq!(errno::sanitize(1000), errno::EIO);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn linux_os_errors() {
+    let os = |x| errno::from_io_error(&Error::from_raw_os_error(x));
+    assert_eq!(os(75), errno::EOVERFLOW);
+    assert_eq!(os(108), errno::ESHUTDOWN);
+    assert_eq!(os(13), errno::EPERM); // EACCES
+    assert_eq!(os(2), errno::EIO); // ENOENT
+}
@@ -30,9 +30,8 @@ fn gen_chunk() -> impl Strategy<Value = Vec<u8>> {
 }
 
 fn get_random_socket(chunks: Vec<Vec<u8>>) -> impl Read + Write {
-    let input: Vec<u8> = chunks.iter().flatten().map(|x| *x).collect();
-    let socket = ReadWrite::new(Cursor::new(input), ::std::io::sink());
-    socket
+    let input: Vec<u8> = chunks.iter().flatten().copied().collect();
+    ReadWrite::new(Cursor::new(input), ::std::io::sink())
 }
 
 proptest! {
@@ -42,6 +42,8 @@ fn gen_chunk() -> impl Strategy<Value = Vec<u8>> {
     prop_oneof! {
         Just(b"\x25\x60\x95\x13".to_vec()),
         Just(b"\x67\x44\x66\x98".to_vec()),
+        Just(b"\x66\x8e\x33\xef".to_vec()),
+        Just(b"\x6e\x8a\x27\x8c".to_vec()),
         Just(b"\x00\x00\x00".to_vec()),
         Just(b"\x00\x00\x00\x00\x00\x00\x00[\x00-\x1F]".to_vec()),
         Just(b"\x00\x00".to_vec()),
@@ -52,9 +54,8 @@ fn gen_chunk() -> impl Strategy<Value = Vec<u8>> {
 }
 
 fn get_random_socket(chunks: Vec<Vec<u8>>) -> impl Read + Write {
-    let input: Vec<u8> = chunks.iter().flatten().map(|x| *x).collect();
-    let socket = ReadWrite::new(Cursor::new(input), ::std::io::sink());
-    socket
+    let input: Vec<u8> = chunks.iter().flatten().copied().collect();
+    ReadWrite::new(Cursor::new(input), ::std::io::sink())
 }
 
 struct FakeStorage;
@@ -74,7 +75,7 @@ impl Write for FakeStorage {
 }
 impl Seek for FakeStorage {
     fn seek(&mut self, _: SeekFrom) -> Result<u64> {
-        Ok(1024_000)
+        Ok(1_024_000)
     }
 }
 
@@ -89,8 +90,10 @@ proptest! {
                                 script in prop::collection::vec(gen_action(),3..12),
         ) {
         let s = get_random_socket(chunks);
-        let mut exp = nbd::client::Export::default();
-        exp.size = SS;
+        let exp = nbd::client::Export {
+            size: SS,
+            ..Default::default()
+        };
         let mut client = nbd::client::NbdClient::new(s, &exp);
 
         let mut buf = vec![0;4096];
@@ -103,13 +106,13 @@ proptest! {
                     client.seek(SeekFrom::Start(pos)).unwrap();
                 },
                 Action::Write(sz) => {
-                    if sz > 0 {
-                        if client.write(&buf[0..sz]).is_ok() { succ += 1; }
+                    if sz > 0 && client.write(&buf[0..sz]).is_ok() {
+                        succ += 1;
                     }
                 },
                 Action::Read(sz) => {
-                    if sz > 0 {
-                        if client.read(&mut buf[0..sz]).is_ok() { succ += 1; }
+                    if sz > 0 && client.read(&mut buf[0..sz]).is_ok() {
+                        succ += 1;
                     }
                 },
             }
@@ -0,0 +1,353 @@
+extern crate byteorder;
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{Error, ErrorKind, Read, Write};
+
+use byteorder::{BigEndian as BE, ReadBytesExt, WriteBytesExt};
+
+use common::socketpair;
+
+fn test_export(name: &str) -> std::io::Result<nbd::Export<u32>> {
+    if name != "sda1" {
+        return Err(Error::new(ErrorKind::NotFound, "no such export"));
+    }
+    Ok(nbd::Export {
+        size: 1_474_560,
+        readonly: true,
+        rotational: true,
+        description: Some("Floppy".to_string()),
+        block_size: Some(nbd::BlockSize {
+            minimum: 512,
+            preferred: 4096,
+            maximum: 65536,
+        }),
+        data: 42,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn go_with_info() {
+    let (s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).unwrap());
+
+    let export = nbd::client::handshake(s1, b"sda1").unwrap();
+    assert_eq!(export.size, 1_474_560);
+    assert!(export.readonly);
+    assert!(export.rotational);
+    assert!(!export.send_trim);
+    assert_eq!(export.name.as_ref().unwrap(), "sda1");
+    assert_eq!(export.description.as_ref().unwrap(), "Floppy");
+    let bs = export.block_size.unwrap();
+    assert_eq!((bs.minimum, bs.preferred, bs.maximum), (512, 4096, 65536));
+
+    assert_eq!(h.join().unwrap(), 42);
+}
+
+#[test]
+fn go_unknown_export() {
+    let (s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).is_err());
+
+    let e = nbd::client::handshake(s1, b"sdb").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    match nbd::Error::from(e) {
+        nbd::Error::Refused { reason, message } => {
+            assert_eq!(reason, nbd::Refusal::UnknownExport);
+            assert_eq!(message, "no such export");
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    assert!(h.join().unwrap());
+}
+
+#[test]
+fn list() {
+    let catalogue = vec![
+        nbd::ListedExport {
+            name: "sda1".to_string(),
+            description: Some("Floppy".to_string()),
+        },
+        nbd::ListedExport {
+            name: "sdb".to_string(),
+            description: None,
+        },
+    ];
+    let options = nbd::server::NegotiationOptions {
+        list: Some(catalogue.clone()),
+        ..Default::default()
+    };
+
+    let (s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::negotiate(s2, &options, test_export).is_err());
+
+    let listed = nbd::client::list_exports(s1).unwrap();
+    assert_eq!(listed, catalogue);
+
+    assert!(h.join().unwrap());
+}
+
+#[test]
+fn legacy_handshake_list() {
+    let catalogue = vec![nbd::ListedExport {
+        name: "sda1".to_string(),
+        description: None,
+    }];
+    let (s1, s2) = socketpair();
+    let served = catalogue.clone();
+    let h = std::thread::spawn(move || {
+        nbd::server::handshake_with_list(s2, &served, test_export).is_err()
+    });
+
+    assert_eq!(nbd::client::list_exports(s1).unwrap(), catalogue);
+
+    assert!(h.join().unwrap());
+}
+
+#[test]
+fn list_disabled() {
+    let (s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).is_err());
+
+    let e = nbd::client::list_exports(s1).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+    match nbd::Error::of(&e) {
+        Some(nbd::Error::Refused { reason, .. }) => assert_eq!(*reason, nbd::Refusal::Policy),
+        _ => panic!("unexpected error {:?}", e),
+    }
+
+    assert!(h.join().unwrap());
+}
+
+// Protocol constants for speaking raw NBD below
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1;
+const NBD_FLAG_NO_ZEROES: u16 = 2;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1;
+const NBD_FLAG_C_NO_ZEROES: u32 = 2;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_LIST: u32 = 3;
+const NBD_OPT_INFO: u32 = 6;
+const NBD_OPT_GO: u32 = 7;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_ERR_UNSUP: u32 = 1 | 1 << 31;
+const NBD_REP_ERR_INVALID: u32 = 3 | 1 << 31;
+const NBD_REP_ERR_UNKNOWN: u32 = 6 | 1 << 31;
+const NBD_REP_ERR_TOO_BIG: u32 = 9 | 1 << 31;
+const NBD_INFO_EXPORT: u16 = 0;
+const NBD_INFO_BLOCK_SIZE: u16 = 3;
+
+/// Read server greeting and send client flags
+fn greet<IO: Read + Write>(c: &mut IO, client_flags: u32) {
+    let mut magic = [0; 16];
+    c.read_exact(&mut magic).unwrap();
+    assert_eq!(&magic, b"NBDMAGICIHAVEOPT");
+    let hs_flags = c.read_u16::<BE>().unwrap();
+    assert_ne!(hs_flags & NBD_FLAG_FIXED_NEWSTYLE, 0);
+    c.write_u32::<BE>(client_flags).unwrap();
+}
+
+fn send_option<IO: Write>(c: &mut IO, opt: u32, data: &[u8]) {
+    try_send_option(c, opt, data).unwrap();
+}
+
+fn try_send_option<IO: Write>(c: &mut IO, opt: u32, data: &[u8]) -> std::io::Result<()> {
+    c.write_all(b"IHAVEOPT")?;
+    c.write_u32::<BE>(opt)?;
+    c.write_u32::<BE>(data.len() as u32)?;
+    c.write_all(data)
+}
+
+/// Read option reply, return its type
+fn read_reply<IO: Read>(c: &mut IO, opt: u32) -> u32 {
+    read_reply_data(c, opt).0
+}
+
+/// Read option reply, return its type and payload
+fn read_reply_data<IO: Read>(c: &mut IO, opt: u32) -> (u32, Vec<u8>) {
+    assert_eq!(c.read_u64::<BE>().unwrap(), 0x3e889045565a9);
+    assert_eq!(c.read_u32::<BE>().unwrap(), opt);
+    let rtype = c.read_u32::<BE>().unwrap();
+    let len = c.read_u32::<BE>().unwrap();
+    let mut data = vec![0; len as usize];
+    c.read_exact(&mut data).unwrap();
+    (rtype, data)
+}
+
+/// Payload of NBD_OPT_INFO or NBD_OPT_GO asking for block size constraints
+fn info_request(name: &[u8]) -> Vec<u8> {
+    let mut v = vec![];
+    v.write_u32::<BE>(name.len() as u32).unwrap();
+    v.extend_from_slice(name);
+    v.write_u16::<BE>(1).unwrap();
+    v.write_u16::<BE>(NBD_INFO_BLOCK_SIZE).unwrap();
+    v
+}
+
+/// Read NBD_REP_INFO replies up to NBD_REP_ACK, return export size and types of the infos
+fn read_infos<IO: Read>(c: &mut IO, opt: u32) -> (u64, Vec<u16>) {
+    let mut size = None;
+    let mut types = vec![];
+    loop {
+        let (rtype, data) = read_reply_data(c, opt);
+        if rtype == NBD_REP_ACK {
+            return (size.unwrap(), types);
+        }
+        assert_eq!(rtype, NBD_REP_INFO);
+        let mut data = &data[..];
+        let typ = data.read_u16::<BE>().unwrap();
+        if typ == NBD_INFO_EXPORT {
+            size = Some(data.read_u64::<BE>().unwrap());
+        }
+        types.push(typ);
+    }
+}
+
+#[test]
+fn info_then_go() {
+    let (mut s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).unwrap());
+
+    greet(&mut s1, NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES);
+    send_option(&mut s1, NBD_OPT_INFO, &info_request(b"sda1"));
+    let (size, types) = read_infos(&mut s1, NBD_OPT_INFO);
+    assert_eq!(size, 1_474_560);
+    assert!(types.contains(&NBD_INFO_BLOCK_SIZE));
+
+    // Unknown export does not end negotiation
+    send_option(&mut s1, NBD_OPT_INFO, &info_request(b"sdb"));
+    assert_eq!(read_reply(&mut s1, NBD_OPT_INFO), NBD_REP_ERR_UNKNOWN);
+
+    send_option(&mut s1, NBD_OPT_GO, &info_request(b"sda1"));
+    let (size, types) = read_infos(&mut s1, NBD_OPT_GO);
+    assert_eq!(size, 1_474_560);
+    assert!(types.contains(&NBD_INFO_EXPORT));
+
+    assert_eq!(h.join().unwrap(), 42);
+}
+
+#[test]
+fn unknown_and_malformed_options() {
+    let (mut s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).unwrap());
+
+    greet(&mut s1, NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES);
+    send_option(&mut s1, 1000, b"future");
+    assert_eq!(read_reply(&mut s1, 1000), NBD_REP_ERR_UNSUP);
+    send_option(&mut s1, NBD_OPT_LIST, b"x");
+    assert_eq!(read_reply(&mut s1, NBD_OPT_LIST), NBD_REP_ERR_INVALID);
+    send_option(&mut s1, NBD_OPT_GO, b"\0\0");
+    assert_eq!(read_reply(&mut s1, NBD_OPT_GO), NBD_REP_ERR_INVALID);
+    send_option(&mut s1, NBD_OPT_INFO, &vec![0; 200000]);
+    assert_eq!(read_reply(&mut s1, NBD_OPT_INFO), NBD_REP_ERR_TOO_BIG);
+
+    send_option(&mut s1, NBD_OPT_EXPORT_NAME, b"sda1");
+    assert_eq!(s1.read_u64::<BE>().unwrap(), 1_474_560);
+    let flags = s1.read_u16::<BE>().unwrap();
+    assert_ne!(flags & NBD_FLAG_READ_ONLY, 0);
+
+    assert_eq!(h.join().unwrap(), 42);
+}
+
+#[test]
+fn plain_newstyle_client() {
+    let (mut s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).unwrap());
+
+    greet(&mut s1, 0);
+    send_option(&mut s1, NBD_OPT_EXPORT_NAME, b"sda1");
+    assert_eq!(s1.read_u64::<BE>().unwrap(), 1_474_560);
+    s1.read_u16::<BE>().unwrap();
+    let mut zeroes = [1; 124];
+    s1.read_exact(&mut zeroes).unwrap();
+    assert_eq!(&zeroes[..], &[0; 124][..]);
+
+    assert_eq!(h.join().unwrap(), 42);
+}
+
+#[test]
+fn plain_newstyle_client_cannot_be_refused() {
+    let cases: [(u32, &[u8]); 3] = [
+        (1000, b"future"),
+        (NBD_OPT_LIST, b"x"),
+        (NBD_OPT_INFO, &[0; 200000]),
+    ];
+    for &(opt, data) in &cases {
+        let (mut s1, s2) = socketpair();
+        let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).is_err());
+
+        greet(&mut s1, 0);
+        // Server may hang up before reading all of it
+        let _ = try_send_option(&mut s1, opt, data);
+        assert!(h.join().unwrap());
+    }
+}
+
+#[test]
+fn too_long_export_name() {
+    let (mut s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).is_err());
+
+    greet(&mut s1, NBD_FLAG_C_FIXED_NEWSTYLE);
+    let _ = try_send_option(&mut s1, NBD_OPT_EXPORT_NAME, &[b'a'; 200000]);
+    assert!(h.join().unwrap());
+}
+
+#[test]
+fn unknown_client_flags() {
+    let (mut s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).is_err());
+
+    greet(&mut s1, NBD_FLAG_C_FIXED_NEWSTYLE | 1 << 20);
+    assert!(h.join().unwrap());
+}
+
+/// Plain newstyle server which only understands NBD_OPT_EXPORT_NAME
+fn minimal_server<IO: Read + Write>(mut c: IO, hs_flags: u16) -> u32 {
+    c.write_all(b"NBDMAGICIHAVEOPT").unwrap();
+    c.write_u16::<BE>(hs_flags).unwrap();
+    let client_flags = c.read_u32::<BE>().unwrap();
+    assert_eq!(c.read_u64::<BE>().unwrap(), 0x49484156454F5054);
+    assert_eq!(c.read_u32::<BE>().unwrap(), NBD_OPT_EXPORT_NAME);
+    let len = c.read_u32::<BE>().unwrap();
+    c.read_exact(&mut vec![0; len as usize]).unwrap();
+    c.write_u64::<BE>(4096).unwrap();
+    c.write_u16::<BE>(1 | NBD_FLAG_READ_ONLY).unwrap();
+    if client_flags & NBD_FLAG_C_NO_ZEROES == 0 {
+        c.write_all(&[0; 124]).unwrap();
+    }
+    client_flags
+}
+
+#[test]
+fn client_no_zeroes() {
+    for &hs_flags in &[0, NBD_FLAG_NO_ZEROES] {
+        let (s1, s2) = socketpair();
+        let h = std::thread::spawn(move || minimal_server(s2, hs_flags));
+
+        let n = nbd::client::negotiate(s1, b"").unwrap();
+        assert_eq!(n.export.size, 4096);
+        assert!(n.export.readonly);
+        assert!(!n.session.fixed_newstyle);
+        assert_eq!(n.session.no_zeroes, hs_flags != 0);
+
+        assert_eq!(h.join().unwrap(), u32::from(hs_flags));
+    }
+}
+
+#[test]
+fn client_session_flags() {
+    let (s1, s2) = socketpair();
+    let h = std::thread::spawn(move || nbd::server::handshake(s2, test_export).unwrap());
+
+    let n = nbd::client::negotiate(s1, b"sda1").unwrap();
+    assert!(n.session.fixed_newstyle);
+    assert!(n.session.no_zeroes);
+    assert_eq!(h.join().unwrap(), 42);
+}
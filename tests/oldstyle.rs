@@ -0,0 +1,136 @@
+#[macro_use]
+extern crate proptest;
+extern crate nbd;
+extern crate pipe;
+extern crate rand;
+extern crate readwrite;
+
+mod common;
+
+use rand::prng::XorShiftRng;
+use rand::{RngCore, SeedableRng};
+
+use proptest::prelude::{prop, ProptestConfig, Strategy};
+
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use common::{socketpair, Pipe};
+
+#[derive(Debug, Eq, PartialEq)]
+enum Action {
+    Seek(u64),
+    Write(usize),
+    ReadAndCheck(usize),
+}
+
+const SS: u64 = 1024 * 1024;
+
+fn gen_action() -> impl Strategy<Value = Action> {
+    prop_oneof! {
+        (0..SS).prop_map(Action::Seek),
+        (0..65536usize).prop_map(Action::Write),
+        (0..65536usize).prop_map(Action::ReadAndCheck),
+    }
+}
+
+fn connect(export: nbd::Export<Cursor<Vec<u8>>>) -> Pipe {
+    let (s1, s2) = socketpair();
+    std::thread::spawn(move || {
+        let _ = nbd::server::oldstyle(s2, export, &Default::default());
+    });
+    s1
+}
+
+#[test]
+fn header_layout() {
+    let mut buf = vec![];
+    nbd::server::oldstyle_header(&mut buf, 0x1234, 0x0001_0203).unwrap();
+    assert_eq!(buf.len(), 152);
+    assert_eq!(&buf[..16], b"NBDMAGIC\x00\x00\x42\x02\x81\x86\x12\x53");
+    assert_eq!(&buf[16..24], &[0, 0, 0, 0, 0, 0, 0x12, 0x34]);
+    assert_eq!(&buf[24..28], &[0, 1, 2, 3]);
+}
+
+#[test]
+fn flags() {
+    let s = connect(nbd::Export {
+        size: 4096,
+        readonly: true,
+        send_trim: true,
+        rotational: true,
+        ..Default::default()
+    });
+    let export = nbd::client::handshake(s, b"").unwrap();
+    assert_eq!(export.size, 4096);
+    assert!(export.readonly);
+    assert!(export.send_trim);
+    assert!(export.rotational);
+    assert!(!export.send_flush);
+    assert!(!export.send_fua);
+}
+
+#[test]
+fn named_export() {
+    let s = connect(nbd::Export::default());
+    assert!(nbd::client::handshake(s, b"foo").is_err());
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        cases: 200,
+        .. ProptestConfig::default()
+    })]
+
+    #[test]
+    fn oldstyle_roundtrip(script in prop::collection::vec(gen_action(),3..12)) {
+        let seed = [5u8;16];
+        let mut r = XorShiftRng::from_seed(seed);
+
+        let mut buf = vec![0;65536];
+        let mut buf2 = vec![0;65536];
+
+        let mut c1 = Cursor::new(vec![0u8;SS as usize]);
+
+        let mut s = connect(nbd::Export {
+            size: SS,
+            data: Cursor::new(vec![0u8;SS as usize]),
+            ..Default::default()
+        });
+        let export = nbd::client::handshake(&mut s, b"").unwrap();
+        assert_eq!(export.size, SS);
+        assert!(export.send_flush);
+        let mut c2 = nbd::client::NbdClient::new(s, &export);
+
+        for i in script {
+            match i {
+                Action::Seek(pos) => {
+                    c1.seek(SeekFrom::Start(pos)).unwrap();
+                    c2.seek(SeekFrom::Start(pos)).unwrap();
+                },
+                Action::Write(mut sz) => {
+                    if sz > (SS - c1.position()) as usize {
+                        sz = (SS - c1.position()) as usize;
+                    }
+                    let bufview = &mut buf[0..sz];
+                    r.fill_bytes(bufview);
+                    c1.write_all(bufview).unwrap();
+                    c2.write_all(bufview).unwrap();
+                },
+                Action::ReadAndCheck(sz) => {
+                    let bufview  = &mut buf[0..sz];
+                    let bufview2 = &mut buf2[0..sz];
+                    let ret1 = c1.read(bufview).unwrap();
+                    let ret2 = c2.read(bufview2).unwrap();
+                    assert!(ret1 == ret2);
+                    assert!(bufview[0..ret1] == bufview2[0..ret2]);
+                },
+            }
+        }
+        c2.flush().unwrap();
+
+        let mut contents = vec![0; SS as usize];
+        c2.seek(SeekFrom::Start(0)).unwrap();
+        c2.read_exact(&mut contents).unwrap();
+        assert!(c1.into_inner() == contents);
+    }
+}
@@ -0,0 +1,179 @@
+extern crate byteorder;
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{Cursor, Read, Result, Seek, SeekFrom, Write};
+
+use byteorder::{BigEndian as BE, ReadBytesExt, WriteBytesExt};
+use common::socketpair;
+use nbd::client::{Command, NbdClient};
+
+/// Read a simple request header, returning type, handle, offset and length
+fn read_request<R: Read>(mut c: R) -> Result<(u16, u64, u64, u32)> {
+    assert_eq!(c.read_u32::<BE>()?, 0x25609513);
+    let _flags = c.read_u16::<BE>()?;
+    let typ = c.read_u16::<BE>()?;
+    let handle = c.read_u64::<BE>()?;
+    let offset = c.read_u64::<BE>()?;
+    let length = c.read_u32::<BE>()?;
+    Ok((typ, handle, offset, length))
+}
+
+fn chunk_header<W: Write>(mut c: W, flags: u16, typ: u16, handle: u64, len: u32) -> Result<()> {
+    c.write_u32::<BE>(0x668e33ef)?;
+    c.write_u16::<BE>(flags)?;
+    c.write_u16::<BE>(typ)?;
+    c.write_u64::<BE>(handle)?;
+    c.write_u32::<BE>(len)
+}
+
+#[test]
+fn simple_replies_out_of_order() {
+    let (s1, mut s2) = socketpair();
+    let h = std::thread::spawn(move || {
+        let reqs: Vec<_> = (0..3).map(|_| read_request(&mut s2).unwrap()).collect();
+        for &(typ, handle, offset, length) in reqs.iter().rev() {
+            assert_eq!(typ, 0);
+            s2.write_u32::<BE>(0x67446698).unwrap();
+            s2.write_u32::<BE>(0).unwrap();
+            s2.write_u64::<BE>(handle).unwrap();
+            s2.write_all(&vec![offset as u8; length as usize]).unwrap();
+        }
+        s2.flush().unwrap();
+    });
+
+    let mut client = NbdClient::new(
+        s1,
+        &nbd::Export::<()> {
+            size: 4096,
+            ..Default::default()
+        },
+    );
+    let mut handles = vec![];
+    for i in 1..4 {
+        let h = client
+            .submit(Command::Read {
+                offset: i,
+                length: 10 * i,
+            })
+            .unwrap();
+        handles.push(h);
+    }
+    assert_eq!(client.pending(), 3);
+
+    for i in (1..4).rev() {
+        let c = client.complete().unwrap();
+        assert_eq!(c.handle, handles[i as usize - 1]);
+        assert_eq!(c.result.unwrap(), vec![i as u8; 10 * i as usize]);
+    }
+    assert_eq!(client.pending(), 0);
+    assert!(client.complete().is_err());
+    h.join().unwrap();
+}
+
+#[test]
+fn interleaved_structured_chunks() {
+    let (s1, mut s2) = socketpair();
+    let h = std::thread::spawn(move || {
+        let (_, h1, o1, _) = read_request(&mut s2).unwrap();
+        let (_, h2, _, _) = read_request(&mut s2).unwrap();
+        s2.read_exact(&mut [0; 3]).unwrap();
+        let (_, h3, o3, _) = read_request(&mut s2).unwrap();
+
+        // first half of the first read
+        chunk_header(&mut s2, 0, 1, h1, 8 + 2).unwrap();
+        s2.write_u64::<BE>(o1).unwrap();
+        s2.write_all(b"ab").unwrap();
+        // error for the write
+        chunk_header(&mut s2, 1, (1 << 15) + 1, h2, 6).unwrap();
+        s2.write_u32::<BE>(28).unwrap(); // ENOSPC
+        s2.write_u16::<BE>(0).unwrap();
+        // the second read is a hole
+        chunk_header(&mut s2, 1, 2, h3, 12).unwrap();
+        s2.write_u64::<BE>(o3).unwrap();
+        s2.write_u32::<BE>(3).unwrap();
+        // second half of the first read
+        chunk_header(&mut s2, 1, 1, h1, 8 + 2).unwrap();
+        s2.write_u64::<BE>(o1 + 2).unwrap();
+        s2.write_all(b"cd").unwrap();
+        s2.flush().unwrap();
+    });
+
+    let mut client = NbdClient::new(
+        s1,
+        &nbd::Export::<()> {
+            size: 4096,
+            ..Default::default()
+        },
+    );
+    let h1 = client
+        .submit(Command::Read {
+            offset: 100,
+            length: 4,
+        })
+        .unwrap();
+    let h2 = client
+        .submit(Command::Write {
+            offset: 0,
+            data: vec![1, 2, 3],
+        })
+        .unwrap();
+    let h3 = client
+        .submit(Command::Read {
+            offset: 200,
+            length: 3,
+        })
+        .unwrap();
+
+    let c = client.complete().unwrap();
+    assert_eq!(c.handle, h2);
+    assert!(c.result.is_err());
+    let c = client.complete().unwrap();
+    assert_eq!(c.handle, h3);
+    assert_eq!(c.result.unwrap(), vec![0, 0, 0]);
+    let c = client.complete().unwrap();
+    assert_eq!(c.handle, h1);
+    assert_eq!(c.result.unwrap(), b"abcd".to_vec());
+    h.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn pipelined_against_server() {
+    // needs a buffered socket, as replies are not read until all requests are sent
+    let (mut s1, mut s2) = std::os::unix::net::UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let n = nbd::server::negotiate(&mut s2, &Default::default(), |_| {
+            Ok(nbd::Export::<()> {
+                size: 1 << 20,
+                ..Default::default()
+            })
+        })
+        .unwrap();
+        let storage = Cursor::new(vec![0; 1 << 20]);
+        let _ = nbd::server::serve(&mut s2, storage, &n.session);
+    });
+
+    let n = nbd::client::negotiate(&mut s1, b"").unwrap();
+    let mut client = NbdClient::from_negotiated(s1, &n);
+    for i in 0..16u8 {
+        client
+            .submit(Command::Write {
+                offset: i as u64 * 65536,
+                data: vec![i; 65536],
+            })
+            .unwrap();
+    }
+    while client.pending() > 0 {
+        client.complete().unwrap().result.unwrap();
+    }
+
+    // Read/Write/Seek still work on top of it
+    let mut buf = vec![0; 2];
+    client.seek(SeekFrom::Start(65535)).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, vec![0, 1]);
+}
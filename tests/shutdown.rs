@@ -0,0 +1,151 @@
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{ErrorKind, Write};
+use std::sync::atomic::Ordering;
+
+use common::{socketpair, FlushCounter};
+use nbd::server::{NegotiationOptions, ShutdownHandle};
+
+fn is_shutdown(e: std::io::Error) -> bool {
+    e.kind() == ErrorKind::ConnectionAborted
+        && match nbd::Error::from(e) {
+            nbd::Error::Device { errno, .. } => errno == nbd::errno::ESHUTDOWN,
+            _ => false,
+        }
+}
+
+#[test]
+fn transmission() {
+    let (mut s1, mut s2) = socketpair();
+
+    let handle = ShutdownHandle::default();
+    let options = NegotiationOptions {
+        shutdown: handle.clone(),
+        ..Default::default()
+    };
+    let (dev, flushes) = FlushCounter::new(65536);
+    let server = std::thread::spawn(move || {
+        let n = nbd::server::negotiate(&mut s2, &options, |_| {
+            Ok(nbd::Export::<()> {
+                size: 65536,
+                ..Default::default()
+            })
+        })
+        .unwrap();
+        nbd::server::serve(&mut s2, dev, &n.session)
+    });
+
+    let export = nbd::client::handshake(&mut s1, b"").unwrap();
+    let mut client = nbd::client::NbdClient::new(s1, &export);
+    client.write_all(&[1; 4096]).unwrap();
+
+    handle.shutdown();
+    assert!(is_shutdown(client.write_all(&[2; 4096]).unwrap_err()));
+    // Server stops after answering, without waiting for the client
+    server.join().unwrap().unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn idle_connection() {
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let (mut s1, s2) = UnixStream::pair().unwrap();
+    let options = NegotiationOptions::default();
+    let handle = options.shutdown.clone();
+    let (dev, flushes) = FlushCounter::new(65536);
+    let server = std::thread::spawn(move || {
+        let conn = s2.try_clone().unwrap();
+        let _hook = options.shutdown.on_shutdown(move || {
+            let _ = conn.shutdown(Shutdown::Both);
+        });
+        let n = nbd::server::negotiate(&s2, &options, |_| {
+            Ok(nbd::Export::<()> {
+                size: 65536,
+                ..Default::default()
+            })
+        })?;
+        nbd::server::serve(&s2, dev, &n.session)
+    });
+
+    let export = nbd::client::handshake(&mut s1, b"").unwrap();
+    let mut client = nbd::client::NbdClient::new(s1, &export);
+    client.write_all(&[1; 4096]).unwrap();
+
+    // Client keeps the connection open, but sends nothing
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    drop(client);
+}
+
+#[test]
+fn hooks() {
+    let handle = ShutdownHandle::default();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let t = tx.clone();
+    let _hook = handle.on_shutdown(move || t.send(1).unwrap());
+    drop(handle.on_shutdown(move || tx.send(2).unwrap()));
+    handle.shutdown();
+    assert_eq!(rx.try_recv(), Ok(1));
+    assert!(rx.try_recv().is_err());
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let _hook = handle.on_shutdown(move || tx.send(3).unwrap());
+    assert_eq!(rx.try_recv(), Ok(3));
+}
+
+#[test]
+fn disconnect_flushes() {
+    let (mut s1, mut s2) = socketpair();
+
+    let handle = ShutdownHandle::default();
+    let options = NegotiationOptions {
+        shutdown: handle.clone(),
+        ..Default::default()
+    };
+    let (dev, flushes) = FlushCounter::new(65536);
+    let server = std::thread::spawn(move || {
+        let n = nbd::server::negotiate(&mut s2, &options, |_| {
+            Ok(nbd::Export::<()> {
+                size: 65536,
+                ..Default::default()
+            })
+        })
+        .unwrap();
+        nbd::server::serve(&mut s2, dev, &n.session)
+    });
+
+    nbd::client::handshake(&mut s1, b"").unwrap();
+    handle.shutdown();
+    // NBD_CMD_DISC
+    let mut req = vec![0x25, 0x60, 0x95, 0x13, 0, 0, 0, 2];
+    req.resize(28, 0);
+    s1.write_all(&req).unwrap();
+
+    server.join().unwrap().unwrap();
+    assert_eq!(flushes.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn negotiation() {
+    let (s1, s2) = socketpair();
+
+    let options = NegotiationOptions::default();
+    options.shutdown.shutdown();
+    std::thread::spawn(move || {
+        let _ = nbd::server::negotiate(s2, &options, |_| Ok(nbd::Export::<()>::default()));
+    });
+
+    let e = nbd::client::handshake(s1, b"").unwrap_err();
+    match nbd::Error::from(e) {
+        nbd::Error::Refused { reason, .. } => assert_eq!(reason, nbd::Refusal::Shutdown),
+        e => panic!("unexpected error {:?}", e),
+    }
+}
@@ -0,0 +1,141 @@
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+use common::socketpair;
+
+const SS: usize = 256 * 1024;
+
+/// Storage which fails to read anything starting from `bad_from`
+struct Faulty {
+    inner: Cursor<Vec<u8>>,
+    bad_from: u64,
+}
+
+impl Read for Faulty {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let pos = self.inner.position();
+        if pos >= self.bad_from {
+            return Err(Error::other("bad sector"));
+        }
+        let len = buf.len().min((self.bad_from - pos) as usize);
+        self.inner.read(&mut buf[..len])
+    }
+}
+impl Write for Faulty {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+impl Seek for Faulty {
+    fn seek(&mut self, sf: SeekFrom) -> Result<u64> {
+        self.inner.seek(sf)
+    }
+}
+
+fn content() -> Vec<u8> {
+    // first 100000 bytes contain data, the rest is zeroes
+    let mut v = vec![0; SS];
+    for (i, x) in v[..100_000].iter_mut().enumerate() {
+        *x = (i % 251) as u8 + 1;
+    }
+    v
+}
+
+fn connect(bad_from: u64, extended: bool) -> nbd::client::NbdClient<impl Read + Write> {
+    let (s1, mut s2) = socketpair();
+
+    std::thread::spawn(move || {
+        let n = nbd::server::negotiate(
+            &mut s2,
+            &Default::default(),
+            |_| -> Result<nbd::Export<Vec<u8>>> {
+                Ok(nbd::Export {
+                    size: SS as u64,
+                    data: content(),
+                    ..Default::default()
+                })
+            },
+        )
+        .unwrap();
+        assert!(n.session.structured_replies);
+        assert_eq!(n.session.extended_headers, extended);
+        let storage = Faulty {
+            inner: Cursor::new(n.export.data),
+            bad_from,
+        };
+        let _ = nbd::server::serve(&mut s2, nbd::server::ReadWriteSeek(storage), &n.session);
+    });
+
+    let mut s1 = s1;
+    if extended {
+        let n = nbd::client::negotiate(&mut s1, b"").unwrap();
+        assert!(n.session.extended_headers);
+        nbd::client::NbdClient::from_negotiated(s1, &n)
+    } else {
+        let export = nbd::client::handshake(&mut s1, b"").unwrap();
+        nbd::client::NbdClient::new(s1, &export)
+    }
+}
+
+fn read_data_and_holes(extended: bool) {
+    let mut client = connect(SS as u64, extended);
+    let mut buf = vec![0xFF; SS];
+    client.read_exact(&mut buf).unwrap();
+    assert!(buf == content());
+
+    client.seek(SeekFrom::Start(99_000)).unwrap();
+    client.write_all(&[0; 2000]).unwrap();
+    client.seek(SeekFrom::Start(98_000)).unwrap();
+    client.read_exact(&mut buf[..4000]).unwrap();
+    assert!(buf[..1000] == content()[98_000..99_000]);
+    assert!(buf[1000..4000].iter().all(|x| *x == 0));
+}
+
+#[test]
+fn structured_read_data_and_holes() {
+    read_data_and_holes(false);
+}
+
+#[test]
+fn extended_read_data_and_holes() {
+    read_data_and_holes(true);
+}
+
+#[test]
+fn structured_read_error_in_the_middle() {
+    read_error_in_the_middle(false);
+}
+
+#[test]
+fn extended_read_error_in_the_middle() {
+    read_error_in_the_middle(true);
+}
+
+fn read_error_in_the_middle(extended: bool) {
+    let mut client = connect(150_000, extended);
+    let mut buf = vec![0; SS];
+
+    client.seek(SeekFrom::Start(0)).unwrap();
+    let e = client.read(&mut buf).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::Other);
+    match nbd::Error::from(e) {
+        nbd::Error::Device { errno, message } => {
+            assert_eq!(errno, 5);
+            assert_eq!(message.unwrap(), "bad sector");
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    // Connection is still usable
+    client.seek(SeekFrom::Start(90_000)).unwrap();
+    client.read_exact(&mut buf[..50_000]).unwrap();
+    assert!(buf[..50_000] == content()[90_000..140_000]);
+}
@@ -0,0 +1,139 @@
+#![cfg(all(feature = "tls", unix))]
+extern crate nbd;
+extern crate rcgen;
+
+use std::convert::TryFrom;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+
+use nbd::client::NbdClient;
+use nbd::rustls;
+use nbd::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use nbd::server::{NegotiationOptions, TlsOptions};
+
+fn configs() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+    let key = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert = key.cert.der().clone();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let server = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![cert.clone()],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.key_pair.serialize_der())),
+        )
+        .unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let client = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    (Arc::new(server), Arc::new(client))
+}
+
+fn spawn_server(
+    s: UnixStream,
+    config: Arc<rustls::ServerConfig>,
+    required: bool,
+) -> std::thread::JoinHandle<std::io::Result<bool>> {
+    std::thread::spawn(move || {
+        let tls = TlsOptions { config, required };
+        let (n, mut c) =
+            nbd::server::negotiate_tls(s, &NegotiationOptions::default(), &tls, |_| {
+                Ok(nbd::Export::<()> {
+                    size: 65536,
+                    ..Default::default()
+                })
+            })?;
+        let is_tls = c.is_tls();
+        // Client disconnects by closing the connection
+        let _ = nbd::server::serve(&mut c, Cursor::new(vec![0; 65536]), &n.session);
+        Ok(is_tls)
+    })
+}
+
+#[test]
+fn roundtrip() {
+    let (server_config, client_config) = configs();
+    let (s1, s2) = UnixStream::pair().unwrap();
+    let server = spawn_server(s2, server_config, true);
+
+    let name = ServerName::try_from("localhost").unwrap();
+    let (n, c) =
+        nbd::client::negotiate_tls(s1, b"", &Default::default(), client_config, name).unwrap();
+    assert!(c.is_tls());
+    assert!(n.session.structured_replies);
+
+    let mut client = NbdClient::from_negotiated(c, &n);
+    client.seek(SeekFrom::Start(4096)).unwrap();
+    client.write_all(&[7; 512]).unwrap();
+    client.seek(SeekFrom::Start(4096)).unwrap();
+    let mut buf = [0; 512];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf[..], &[7; 512][..]);
+
+    drop(client);
+    assert!(server.join().unwrap().unwrap());
+}
+
+#[test]
+fn plain_client_is_refused() {
+    let (server_config, _) = configs();
+    let (s1, s2) = UnixStream::pair().unwrap();
+    let _server = spawn_server(s2, server_config, true);
+
+    let e = nbd::client::negotiate_with(s1, b"", &Default::default()).unwrap_err();
+    match nbd::Error::from(e) {
+        nbd::Error::Refused { reason, .. } => assert_eq!(reason, nbd::Refusal::TlsRequired),
+        e => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[test]
+fn optional_tls() {
+    let (server_config, _) = configs();
+    let (s1, s2) = UnixStream::pair().unwrap();
+    let server = spawn_server(s2, server_config, false);
+
+    let n = nbd::client::negotiate_with(&s1, b"", &Default::default()).unwrap();
+    assert_eq!(n.export.size, 65536);
+    drop(NbdClient::from_negotiated(&s1, &n));
+    drop(s1);
+    assert!(!server.join().unwrap().unwrap());
+}
+
+#[test]
+fn wrong_server_name() {
+    let (server_config, client_config) = configs();
+    let (s1, s2) = UnixStream::pair().unwrap();
+    let server = spawn_server(s2, server_config, true);
+
+    let name = ServerName::try_from("example.com").unwrap();
+    assert!(nbd::client::negotiate_tls(s1, b"", &Default::default(), client_config, name).is_err());
+    assert!(server.join().unwrap().is_err());
+}
+
+#[test]
+fn tls_not_offered() {
+    let (_, client_config) = configs();
+    let (s1, s2) = UnixStream::pair().unwrap();
+    std::thread::spawn(move || {
+        let _ = nbd::server::negotiate(s2, &NegotiationOptions::default(), |_| {
+            Ok(nbd::Export::<()>::default())
+        });
+    });
+
+    let name = ServerName::try_from("localhost").unwrap();
+    let e =
+        nbd::client::negotiate_tls(s1, b"", &Default::default(), client_config, name).unwrap_err();
+    match nbd::Error::from(e) {
+        nbd::Error::Refused { reason, .. } => assert_eq!(reason, nbd::Refusal::Unsupported),
+        e => panic!("unexpected error {:?}", e),
+    }
+}
@@ -0,0 +1,207 @@
+extern crate byteorder;
+extern crate nbd;
+extern crate pipe;
+extern crate readwrite;
+
+mod common;
+
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+use byteorder::{BigEndian as BE, ReadBytesExt, WriteBytesExt};
+use common::{socketpair, spawn_server, Client, Pipe};
+use nbd::client::{Command, NbdClient, NbdExt};
+
+const SIZE: u64 = 65536;
+
+fn connect(block_size: Option<nbd::BlockSize>) -> NbdClient<Pipe> {
+    let (s1, s2) = socketpair();
+    connect_over(s1, s2, block_size)
+}
+
+fn connect_over<S1, S2>(s1: S1, s2: S2, block_size: Option<nbd::BlockSize>) -> NbdClient<S1>
+where
+    S1: Read + Write,
+    S2: Read + Write + Send + 'static,
+{
+    let export = nbd::Export {
+        size: SIZE,
+        send_trim: true,
+        block_size,
+        ..Default::default()
+    };
+    // Backend is larger than the export
+    let dev = Cursor::new(vec![0; 2 * SIZE as usize]);
+    spawn_server(s2, dev, export, Default::default());
+    common::client(s1, &Client::negotiate())
+}
+
+fn run(client: &mut NbdClient<impl Read + Write>, cmd: Command) -> std::io::Result<Vec<u8>> {
+    let h = client.submit(cmd).unwrap();
+    let c = client.complete().unwrap();
+    assert_eq!(c.handle, h);
+    c.result
+}
+
+#[test]
+fn past_the_end() {
+    let mut client = connect(None);
+    let read = |offset, length| Command::Read { offset, length };
+
+    assert!(run(&mut client, read(SIZE - 4096, 4096)).is_ok());
+    assert!(run(&mut client, read(SIZE - 4096, 8192)).is_err());
+    assert!(run(&mut client, read(u64::MAX, 2)).is_err());
+    let write = Command::Write {
+        offset: SIZE - 10,
+        data: vec![1; 20],
+    };
+    assert!(run(&mut client, write).is_err());
+    let trim = Command::Trim {
+        offset: SIZE,
+        length: 1,
+    };
+    assert!(run(&mut client, trim).is_err());
+
+    // Rejected write payload was consumed, connection is in sync
+    assert_eq!(run(&mut client, read(0, 3)).unwrap(), [0; 3]);
+}
+
+#[test]
+fn alignment() {
+    let mut client = connect(Some(nbd::BlockSize {
+        minimum: 512,
+        preferred: 4096,
+        maximum: 65536,
+    }));
+
+    let write = |offset, len| Command::Write {
+        offset,
+        data: vec![1; len],
+    };
+    assert!(run(&mut client, write(512, 1024)).is_ok());
+    assert!(run(&mut client, write(100, 512)).is_err());
+    assert!(run(&mut client, write(0, 100)).is_err());
+    let read = Command::Read {
+        offset: 512,
+        length: 512,
+    };
+    assert_eq!(run(&mut client, read).unwrap(), [1; 512]);
+}
+
+#[cfg(unix)]
+#[test]
+fn oversized_payload_is_not_read() {
+    // Needs a buffered connection, as nobody reads the payload
+    let (s1, s2) = std::os::unix::net::UnixStream::pair().unwrap();
+    let bs = nbd::BlockSize {
+        minimum: 1,
+        preferred: 4096,
+        maximum: 4096,
+    };
+    let mut client = connect_over(s1, s2, Some(bs));
+
+    // Server rejects it and hangs up instead of reading the payload
+    let e = client.submit(Command::Write {
+        offset: 0,
+        data: vec![1; 8192],
+    });
+    let e = e.and_then(|_| client.complete().and_then(|c| c.result));
+    assert!(e.is_err());
+}
+
+/// Client which is not told about block size constraints: it selects the export
+/// with NBD_OPT_EXPORT_NAME or with NBD_OPT_GO without asking for NBD_INFO_BLOCK_SIZE
+fn connect_uninformed(go: bool) -> NbdClient<Pipe> {
+    let (mut s1, s2) = socketpair();
+    let export = nbd::Export {
+        size: SIZE,
+        block_size: Some(nbd::BlockSize {
+            minimum: 512,
+            preferred: 4096,
+            maximum: 4096,
+        }),
+        ..Default::default()
+    };
+    spawn_server(
+        s2,
+        Cursor::new(vec![0; SIZE as usize]),
+        export,
+        Default::default(),
+    );
+
+    let mut greeting = [0; 18];
+    s1.read_exact(&mut greeting).unwrap();
+    // NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES
+    s1.write_u32::<BE>(3).unwrap();
+    s1.write_all(b"IHAVEOPT").unwrap();
+    if go {
+        // NBD_OPT_GO for the default export, no information requests
+        s1.write_u32::<BE>(7).unwrap();
+        s1.write_u32::<BE>(6).unwrap();
+        s1.write_all(&[0; 6]).unwrap();
+        loop {
+            s1.read_u64::<BE>().unwrap();
+            s1.read_u32::<BE>().unwrap();
+            let rtype = s1.read_u32::<BE>().unwrap();
+            let len = s1.read_u32::<BE>().unwrap();
+            s1.read_exact(&mut vec![0; len as usize]).unwrap();
+            // NBD_REP_ACK
+            if rtype == 1 {
+                break;
+            }
+        }
+    } else {
+        // NBD_OPT_EXPORT_NAME
+        s1.write_u32::<BE>(1).unwrap();
+        s1.write_u32::<BE>(0).unwrap();
+        s1.read_u64::<BE>().unwrap();
+        s1.read_u16::<BE>().unwrap();
+    }
+    let export = nbd::Export {
+        size: SIZE,
+        ..Default::default()
+    };
+    NbdClient::new(s1, &export)
+}
+
+#[test]
+fn unadvertised_block_size_is_not_enforced() {
+    for &go in &[false, true] {
+        let mut client = connect_uninformed(go);
+        let read = Command::Read {
+            offset: 1,
+            length: 1,
+        };
+        assert_eq!(run(&mut client, read).unwrap(), [0]);
+        let write = Command::Write {
+            offset: 3,
+            data: vec![1; 5000],
+        };
+        assert!(run(&mut client, write).is_ok());
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn client_respects_maximum_block_size() {
+    // Buffered connection, so that an oversized request fails instead of blocking
+    let (s1, s2) = std::os::unix::net::UnixStream::pair().unwrap();
+    let bs = nbd::BlockSize {
+        minimum: 512,
+        preferred: 4096,
+        maximum: 4096,
+    };
+    let mut client = connect_over(s1, s2, Some(bs));
+
+    client.seek(SeekFrom::Start(512)).unwrap();
+    client.write_all(&[5; SIZE as usize - 1024]).unwrap();
+    let mut buf = vec![0; SIZE as usize];
+    client.seek(SeekFrom::Start(0)).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert!(buf[..512].iter().all(|x| *x == 0));
+    assert!(buf[512..SIZE as usize - 512].iter().all(|x| *x == 5));
+
+    client.seek(SeekFrom::Start(0)).unwrap();
+    client.trim(SIZE as usize).unwrap();
+    client.read_exact(&mut buf).unwrap();
+    assert!(buf.iter().all(|x| *x == 0));
+}